@@ -0,0 +1,189 @@
+//! Access control for private rooms.
+//! A room is private if it was created with a password or as invite only.
+//! Passwords are only kept as salted PBKDF2 hashes.
+//! Invites are tokens signed by the server for a single room, so they can be
+//! verified without storing them. A revoked invite is remembered by its id,
+//! which is the first part of the token. Invites are also signed over the nonce
+//! of the room, so they do not open a room created later under the same name.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+
+use session::generate_token;
+
+const PBKDF2_ITERATIONS: usize = 10_000;
+const SALT_LENGTH: usize = 16;
+const HASH_LENGTH: usize = 32;
+
+/// An invite to a private room.
+pub struct Invite {
+    pub id: String,
+    /// The token a user enters the room with, `<id>.<expiry>.<signature>`.
+    pub token: String,
+    /// When the invite expires, in seconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+impl Invite {
+    /// Mints an invite to the room with the nonce, that expires after the time to live.
+    pub fn new(secret: &str, room: &str, nonce: &str, ttl: Duration) -> Invite {
+        let id = generate_token();
+        let expires_at = now() + ttl.as_secs();
+        let signature = sign(secret, room, nonce, &id, expires_at);
+        Invite {
+            token: format!("{}.{}.{}", id, expires_at, signature),
+            id,
+            expires_at,
+        }
+    }
+}
+
+/// Verifies that an invite token was minted for the room with the nonce and has not expired.
+/// Returns the id of the invite, so the caller can check it has not been revoked.
+pub fn verify_invite<'a>(secret: &str, room: &str, nonce: &str, token: &'a str) -> Option<&'a str> {
+    let mut parts = token.split('.');
+    let (id, expires_at, signature) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(id), Some(expires_at), Some(signature), None) => (id, expires_at.parse::<u64>().ok()?, signature),
+        _ => return None,
+    };
+
+    let expected = sign(secret, room, nonce, id, expires_at);
+    let valid = expected.len() == signature.len() && memcmp::eq(expected.as_bytes(), signature.as_bytes());
+    if valid && now() < expires_at {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+/// The id of an invite, given either the id itself or the whole token.
+pub fn invite_id(invite: &str) -> &str {
+    invite.split('.').next().unwrap_or_default()
+}
+
+/// Derives the secret invites are signed with on the network of a tenant,
+/// so an invite to a room of one tenant does not open the room of the same name of another tenant.
+pub fn tenant_secret(secret: &str, tenant: &str) -> String {
+    let key = PKey::hmac(secret.as_bytes()).expect("Could not create the invite key");
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).expect("Could not derive the invite secret");
+    signer.update(format!("tenant\n{}", tenant).as_bytes())
+        .and_then(|()| signer.sign_to_vec())
+        .map(|secret| base64::encode_config(&secret, base64::URL_SAFE_NO_PAD))
+        .expect("Could not derive the invite secret")
+}
+
+/// Hashes a password with a random salt, as `<salt>$<hash>`.
+pub fn hash_password(password: &str) -> String {
+    let mut salt = [0; SALT_LENGTH];
+    rand_bytes(&mut salt).expect("Could not generate random bytes");
+    let hash = pbkdf2(password, &salt);
+    format!("{}${}", base64::encode(&salt), base64::encode(&hash))
+}
+
+/// Checks a password against a hash made by `hash_password`.
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    let mut parts = hash.splitn(2, '$');
+    let salt = parts.next().and_then(|salt| base64::decode(salt).ok());
+    let expected = parts.next().and_then(|hash| base64::decode(hash).ok());
+    match (salt, expected) {
+        (Some(salt), Some(expected)) => {
+            let hash = pbkdf2(password, &salt);
+            expected.len() == hash.len() && memcmp::eq(&expected, &hash)
+        },
+        _ => false,
+    }
+}
+
+fn pbkdf2(password: &str, salt: &[u8]) -> [u8; HASH_LENGTH] {
+    let mut hash = [0; HASH_LENGTH];
+    pbkdf2_hmac(password.as_bytes(), salt, PBKDF2_ITERATIONS, MessageDigest::sha256(), &mut hash)
+        .expect("Could not hash the password");
+    hash
+}
+
+fn sign(secret: &str, room: &str, nonce: &str, id: &str, expires_at: u64) -> String {
+    let key = PKey::hmac(secret.as_bytes()).expect("Could not create the invite key");
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).expect("Could not sign the invite");
+    let message = format!("{}\n{}\n{}\n{}", room, nonce, id, expires_at);
+    signer.update(message.as_bytes())
+        .and_then(|()| signer.sign_to_vec())
+        .map(|signature| base64::encode_config(&signature, base64::URL_SAFE_NO_PAD))
+        .expect("Could not sign the invite")
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "invite secret";
+    const NONCE: &str = "nonce";
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    #[test]
+    fn verifies_an_invite_for_its_room() {
+        let invite = Invite::new(SECRET, "vault", NONCE, DAY);
+        assert_eq!(verify_invite(SECRET, "vault", NONCE, &invite.token), Some(invite.id.as_str()));
+        assert_eq!(invite_id(&invite.token), invite.id);
+        assert_eq!(invite_id(&invite.id), invite.id);
+    }
+
+    #[test]
+    fn rejects_invites_for_other_rooms_nonces_or_secrets() {
+        let invite = Invite::new(SECRET, "vault", NONCE, DAY);
+        assert_eq!(verify_invite(SECRET, "lobby", NONCE, &invite.token), None);
+        assert_eq!(verify_invite("another secret", "vault", NONCE, &invite.token), None);
+        assert_eq!(verify_invite(&tenant_secret(SECRET, "other"), "vault", NONCE, &invite.token), None);
+        assert_eq!(verify_invite(SECRET, "vault", "another nonce", &invite.token), None);
+    }
+
+    #[test]
+    fn rejects_expired_and_tampered_invites() {
+        let expired = Invite::new(SECRET, "vault", NONCE, Duration::from_secs(0));
+        assert_eq!(verify_invite(SECRET, "vault", NONCE, &expired.token), None);
+
+        let invite = Invite::new(SECRET, "vault", NONCE, DAY);
+        let extended = invite.token.replacen(&invite.expires_at.to_string(), &(invite.expires_at + 1).to_string(), 1);
+        assert_eq!(verify_invite(SECRET, "vault", NONCE, &extended), None);
+        assert_eq!(verify_invite(SECRET, "vault", NONCE, &invite.id), None);
+        assert_eq!(verify_invite(SECRET, "vault", NONCE, &format!("{}.extra", invite.token)), None);
+    }
+
+    #[test]
+    fn derives_a_secret_per_tenant() {
+        assert_eq!(tenant_secret(SECRET, "chat"), tenant_secret(SECRET, "chat"));
+        assert_ne!(tenant_secret(SECRET, "chat"), tenant_secret(SECRET, "video"));
+    }
+
+    #[test]
+    fn verifies_hashed_passwords() {
+        let hash = hash_password("hunter2");
+        assert!(!hash.contains("hunter2"));
+        assert!(verify_password(&hash, "hunter2"));
+        assert!(!verify_password(&hash, "hunter3"));
+        assert!(!verify_password(&hash, ""));
+    }
+
+    #[test]
+    fn salts_every_hash() {
+        assert_ne!(hash_password("hunter2"), hash_password("hunter2"));
+    }
+
+    #[test]
+    fn rejects_malformed_hashes() {
+        assert!(!verify_password("", "hunter2"));
+        assert!(!verify_password("no separator", "hunter2"));
+        assert!(!verify_password("!!!$!!!", "hunter2"));
+    }
+}
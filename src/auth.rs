@@ -0,0 +1,323 @@
+//! Authentication of handshakes with JSON Web Tokens.
+//! Without it anyone can connect with any username. With it, a node must present
+//! a token signed by the configured key, and is registered with the username
+//! found in one of the token's claims.
+//!
+//! Tokens are signed with HS256 using a shared secret, or with RS256 or ES256
+//! using a private key whose public key is given in a PEM file.
+//! A token must have an `exp` claim, and is rejected after it expires.
+
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use openssl::bn::BigNum;
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::{Id, PKey, Public};
+use openssl::sign::{Signer, Verifier};
+use serde_json::Value;
+
+use config::Config;
+
+/// How many seconds the clocks of the token issuer and the server may differ.
+const LEEWAY: u64 = 30;
+
+#[derive(Clone)]
+enum Key {
+    Hmac(Vec<u8>),
+    Rsa(PKey<Public>),
+    Ec(PKey<Public>),
+}
+
+/// Verifies tokens and extracts the username from them.
+#[derive(Clone)]
+pub struct Authenticator {
+    key: Key,
+    username_claim: String,
+}
+
+impl Authenticator {
+    /// Creates an authenticator from the configured secret or public key,
+    /// or None if authentication is not enabled.
+    pub fn from_config(config: &Config) -> Result<Option<Authenticator>, String> {
+        let key = match (config.jwt_secret.as_ref(), config.jwt_public_key.as_ref()) {
+            (Some(_), Some(_)) => {
+                return Err("Configure either a JWT secret or a JWT public key, not both".to_string())
+            },
+            (Some(secret), None) => Key::Hmac(secret.as_bytes().to_vec()),
+            (None, Some(path)) => {
+                let pem = fs::read(path)
+                    .map_err(|error| format!("Could not read the JWT public key {:?}: {}", path, error))?;
+                let key = PKey::public_key_from_pem(&pem)
+                    .map_err(|error| format!("Could not parse the JWT public key {:?}: {}", path, error))?;
+                match key.id() {
+                    Id::RSA => Key::Rsa(key),
+                    Id::EC => Key::Ec(key),
+                    _ => return Err(format!("The JWT public key {:?} is neither an RSA nor an EC key", path)),
+                }
+            },
+            (None, None) => return Ok(None),
+        };
+
+        Ok(Some(Authenticator {
+            key,
+            username_claim: config.jwt_username_claim.clone(),
+        }))
+    }
+
+    /// Verifies the token and returns the username it was issued for.
+    pub fn authenticate(&self, token: &str) -> Result<String, String> {
+        let mut parts = token.split('.');
+        let (header, claims, signature) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(header), Some(claims), Some(signature), None) => (header, claims, signature),
+            _ => return Err("The token does not have three parts".to_string()),
+        };
+
+        let header = decode_json(header)?;
+        let algorithm = header.get("alg").and_then(Value::as_str).unwrap_or_default();
+        let signed = &token[..header_and_claims_length(token)];
+        let signature = decode(signature)?;
+        if !self.verify(algorithm, signed.as_bytes(), &signature)? {
+            return Err("The signature of the token is not valid".to_string());
+        }
+
+        let claims = decode_json(claims)?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or_default();
+        match claims.get("exp").and_then(Value::as_u64) {
+            Some(expires_at) if now > expires_at.saturating_add(LEEWAY) => return Err("The token has expired".to_string()),
+            Some(_) => {},
+            None => return Err("The token has no expiry".to_string()),
+        }
+        if claims.get("nbf").and_then(Value::as_u64).is_some_and(|not_before| now + LEEWAY < not_before) {
+            return Err("The token is not valid yet".to_string());
+        }
+
+        match claims.get(&self.username_claim).and_then(Value::as_str) {
+            Some(username) if !username.is_empty() => Ok(username.to_string()),
+            _ => Err(format!("The token has no {:?} claim with a username", self.username_claim)),
+        }
+    }
+
+    fn verify(&self, algorithm: &str, signed: &[u8], signature: &[u8]) -> Result<bool, String> {
+        let openssl_error = |error: openssl::error::ErrorStack| error.to_string();
+        match (algorithm, &self.key) {
+            ("HS256", Key::Hmac(secret)) => {
+                let key = PKey::hmac(secret).map_err(openssl_error)?;
+                let mut signer = Signer::new(MessageDigest::sha256(), &key).map_err(openssl_error)?;
+                signer.update(signed).map_err(openssl_error)?;
+                let expected = signer.sign_to_vec().map_err(openssl_error)?;
+                Ok(expected.len() == signature.len() && memcmp::eq(&expected, signature))
+            },
+            ("RS256", Key::Rsa(key)) => {
+                let mut verifier = Verifier::new(MessageDigest::sha256(), key).map_err(openssl_error)?;
+                verifier.update(signed).map_err(openssl_error)?;
+                Ok(verifier.verify(signature).unwrap_or(false))
+            },
+            ("ES256", Key::Ec(key)) => {
+                // JWTs carry the raw r and s of the signature, openssl wants them DER encoded
+                if signature.len() != 64 {
+                    return Ok(false);
+                }
+                let r = BigNum::from_slice(&signature[..32]).map_err(openssl_error)?;
+                let s = BigNum::from_slice(&signature[32..]).map_err(openssl_error)?;
+                let der = EcdsaSig::from_private_components(r, s)
+                    .and_then(|signature| signature.to_der())
+                    .map_err(openssl_error)?;
+                let mut verifier = Verifier::new(MessageDigest::sha256(), key).map_err(openssl_error)?;
+                verifier.update(signed).map_err(openssl_error)?;
+                Ok(verifier.verify(&der).unwrap_or(false))
+            },
+            _ => Err(format!("The algorithm {:?} is not accepted", algorithm)),
+        }
+    }
+}
+
+/// Picks the token out of the values of a `Sec-WebSocket-Protocol` header.
+/// Browsers can not set headers on WebSockets, so they offer the token as a
+/// subprotocol instead, e.g. `new WebSocket(url, ["access_token", token])`.
+pub fn token_from_protocols<'a>(protocols: &[&'a str]) -> Option<&'a str> {
+    protocols.iter().find(|protocol| protocol.matches('.').count() == 2).cloned()
+}
+
+/// Picks the token out of an `Authorization: Bearer <token>` header.
+pub fn token_from_authorization(header: &[u8]) -> Option<&str> {
+    let header = std::str::from_utf8(header).ok()?;
+    let mut parts = header.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => Some(token.trim()),
+        _ => None,
+    }
+}
+
+fn header_and_claims_length(token: &str) -> usize {
+    token.rfind('.').unwrap_or_default()
+}
+
+fn decode(part: &str) -> Result<Vec<u8>, String> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| "The token is not valid base64".to_string())
+}
+
+fn decode_json(part: &str) -> Result<Value, String> {
+    serde_json::from_slice(&decode(part)?)
+        .map_err(|_| "The token is not valid JSON".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::pkey::Private;
+    use openssl::rsa::Rsa;
+
+    const SECRET: &str = "correct horse battery staple";
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn encode(value: &Value) -> String {
+        base64::encode_config(value.to_string().as_bytes(), base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Builds a token, signing it with the HMAC secret or the private key.
+    fn token(algorithm: &str, claims: Value, key: Option<&PKey<Private>>) -> String {
+        let signed = format!("{}.{}", encode(&json!({"alg": algorithm, "typ": "JWT"})), encode(&claims));
+        let signature = match key {
+            None => {
+                let key = PKey::hmac(SECRET.as_bytes()).unwrap();
+                let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+                signer.update(signed.as_bytes()).unwrap();
+                signer.sign_to_vec().unwrap()
+            },
+            Some(key) => {
+                let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+                signer.update(signed.as_bytes()).unwrap();
+                let signature = signer.sign_to_vec().unwrap();
+                if key.id() == Id::EC {
+                    // JWTs carry the raw r and s instead of the DER encoding
+                    let signature = EcdsaSig::from_der(&signature).unwrap();
+                    let mut raw = signature.r().to_vec_padded(32).unwrap();
+                    raw.extend(signature.s().to_vec_padded(32).unwrap());
+                    raw
+                } else {
+                    signature
+                }
+            },
+        };
+        format!("{}.{}", signed, base64::encode_config(&signature, base64::URL_SAFE_NO_PAD))
+    }
+
+    fn hmac_authenticator() -> Authenticator {
+        let config = Config { jwt_secret: Some(SECRET.to_string()), ..Config::default() };
+        Authenticator::from_config(&config).unwrap().unwrap()
+    }
+
+    fn public_authenticator(private_key: &PKey<Private>) -> Authenticator {
+        let public_key = PKey::public_key_from_pem(&private_key.public_key_to_pem().unwrap()).unwrap();
+        let key = if public_key.id() == Id::EC { Key::Ec(public_key) } else { Key::Rsa(public_key) };
+        Authenticator { key, username_claim: "sub".to_string() }
+    }
+
+    fn valid_claims() -> Value {
+        json!({"sub": "alice", "exp": now() + 60})
+    }
+
+    #[test]
+    fn is_disabled_without_a_key() {
+        assert!(Authenticator::from_config(&Config::default()).unwrap().is_none());
+        let both = Config {
+            jwt_secret: Some(SECRET.to_string()),
+            jwt_public_key: Some("/dev/null".to_string()),
+            ..Config::default()
+        };
+        assert!(Authenticator::from_config(&both).is_err());
+    }
+
+    #[test]
+    fn accepts_hs256_tokens() {
+        let token = token("HS256", valid_claims(), None);
+        assert_eq!(hmac_authenticator().authenticate(&token), Ok("alice".to_string()));
+    }
+
+    #[test]
+    fn accepts_rs256_tokens() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let token = token("RS256", valid_claims(), Some(&key));
+        assert_eq!(public_authenticator(&key).authenticate(&token), Ok("alice".to_string()));
+    }
+
+    #[test]
+    fn accepts_es256_tokens() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let token = token("ES256", valid_claims(), Some(&key));
+        assert_eq!(public_authenticator(&key).authenticate(&token), Ok("alice".to_string()));
+    }
+
+    #[test]
+    fn rejects_other_algorithms() {
+        let authenticator = hmac_authenticator();
+        assert!(authenticator.authenticate(&token("none", valid_claims(), None)).is_err());
+        assert!(authenticator.authenticate(&token("HS512", valid_claims(), None)).is_err());
+
+        // A public key must not be usable as an HMAC secret
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        assert!(public_authenticator(&key).authenticate(&token("HS256", valid_claims(), None)).is_err());
+    }
+
+    #[test]
+    fn rejects_tampered_tokens() {
+        let authenticator = hmac_authenticator();
+        let token = token("HS256", valid_claims(), None);
+        let forged_claims = encode(&json!({"sub": "mallory", "exp": now() + 60}));
+        let parts: Vec<&str> = token.split('.').collect();
+        let forged = format!("{}.{}.{}", parts[0], forged_claims, parts[2]);
+        assert!(authenticator.authenticate(&forged).is_err());
+        assert!(authenticator.authenticate(&format!("{}.{}", parts[0], parts[1])).is_err());
+        assert!(authenticator.authenticate("not a token").is_err());
+    }
+
+    #[test]
+    fn checks_the_expiry() {
+        let authenticator = hmac_authenticator();
+        let expired = token("HS256", json!({"sub": "alice", "exp": now() - LEEWAY - 1}), None);
+        assert!(authenticator.authenticate(&expired).is_err());
+        let within_leeway = token("HS256", json!({"sub": "alice", "exp": now() - 1}), None);
+        assert!(authenticator.authenticate(&within_leeway).is_ok());
+        let without_expiry = token("HS256", json!({"sub": "alice"}), None);
+        assert!(authenticator.authenticate(&without_expiry).is_err());
+        let far_future = token("HS256", json!({"sub": "alice", "exp": u64::MAX}), None);
+        assert!(authenticator.authenticate(&far_future).is_ok());
+    }
+
+    #[test]
+    fn checks_not_before() {
+        let authenticator = hmac_authenticator();
+        let early = token("HS256", json!({"sub": "alice", "exp": now() + 600, "nbf": now() + LEEWAY + 60}), None);
+        assert!(authenticator.authenticate(&early).is_err());
+        let started = token("HS256", json!({"sub": "alice", "exp": now() + 600, "nbf": now()}), None);
+        assert!(authenticator.authenticate(&started).is_ok());
+    }
+
+    #[test]
+    fn requires_the_username_claim() {
+        let authenticator = hmac_authenticator();
+        assert!(authenticator.authenticate(&token("HS256", json!({"exp": now() + 60}), None)).is_err());
+        assert!(authenticator.authenticate(&token("HS256", json!({"sub": "", "exp": now() + 60}), None)).is_err());
+    }
+
+    #[test]
+    fn finds_the_token_in_headers_and_protocols() {
+        assert_eq!(token_from_authorization(b"Bearer a.b.c"), Some("a.b.c"));
+        assert_eq!(token_from_authorization(b"bearer  a.b.c "), Some("a.b.c"));
+        assert_eq!(token_from_authorization(b"Basic YWxpY2U6"), None);
+        assert_eq!(token_from_protocols(&["access_token", "a.b.c"]), Some("a.b.c"));
+        assert_eq!(token_from_protocols(&["chat"]), None);
+    }
+}
@@ -0,0 +1,51 @@
+//! Calls let a user invite another user to connect, and track the answer.
+//! The callee is rung in-band if it is online, or with a push notification if it is not.
+//! A call keeps ringing until the callee accepts or rejects it, the caller cancels it,
+//! or the ring timeout passes. Both users are told about every change of the call's state.
+
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+/// The state of a call. Every state but ringing ends the call.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CallState {
+    Ringing,
+    Accepted,
+    Rejected,
+    Cancelled,
+    Timeout,
+}
+
+impl CallState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CallState::Ringing => "ringing",
+            CallState::Accepted => "accepted",
+            CallState::Rejected => "rejected",
+            CallState::Cancelled => "cancelled",
+            CallState::Timeout => "timeout",
+        }
+    }
+}
+
+/// A call that is still ringing.
+pub struct Call {
+    pub id: String,
+    pub caller: String,
+    pub callee: String,
+    /// Sent to the callee with the ring, e.g. to tell it what kind of call it is.
+    pub payload: Value,
+    /// Identifies the ring timeout scheduled on the caller's connection.
+    pub timeout_token: usize,
+    pub started_at: Instant,
+}
+
+impl Call {
+    /// Checks if the call has been ringing for longer than the ring timeout.
+    /// The scheduled timeout normally ends the call first, but it is lost
+    /// if the caller's connection is replaced.
+    pub fn is_expired(&self, ring_timeout: Duration) -> bool {
+        self.started_at.elapsed() >= ring_timeout
+    }
+}
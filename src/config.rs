@@ -0,0 +1,547 @@
+//! Tunable settings of the signaling server.
+//! Every setting is an optional command line flag, shared by the
+//! plain and the secure server, and falls back to a sensible default.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::{Arg, ArgMatches};
+
+use session::generate_token;
+use tenant::DEFAULT_TENANT;
+
+/// What to do when a node connects with a username that is already in use.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DuplicatePolicy {
+    /// Refuse the new connection.
+    #[default]
+    RejectNew,
+    /// Close the connection currently holding the username and hand it to the new one.
+    ReplaceOld,
+    /// Give the new connection a free variation of the username, e.g. `alice-2`.
+    AutoSuffix,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<DuplicatePolicy, String> {
+        match policy {
+            "reject-new" => Ok(DuplicatePolicy::RejectNew),
+            "replace-old" => Ok(DuplicatePolicy::ReplaceOld),
+            "auto-suffix" => Ok(DuplicatePolicy::AutoSuffix),
+            _ => Err(format!("Unknown policy for duplicate usernames {:?}", policy)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The number of messages a node may send each second, or no limit.
+    pub max_messages_per_second: Option<u32>,
+    pub duplicate_usernames: DuplicatePolicy,
+    /// How long the session of a disconnected node can be resumed, or never.
+    pub resume_grace_period: Option<Duration>,
+    /// How long an empty room is kept before it is removed.
+    pub room_linger: Duration,
+    /// The number of messages that can be queued for an offline user.
+    pub queue_size: usize,
+    /// The most messages queued for all offline users together.
+    pub queue_total: usize,
+    /// How long a queued message waits for the user to connect.
+    pub queue_ttl: Duration,
+    /// How long a call rings before it times out.
+    pub ring_timeout: Duration,
+    /// The STUN and TURN servers handed to clients, e.g. `stun:stun.example.com:3478`.
+    pub ice_urls: Vec<String>,
+    /// The secret shared with the TURN server, used to sign ephemeral credentials.
+    pub turn_secret: Option<String>,
+    /// How long ephemeral TURN credentials are valid.
+    pub turn_ttl: Duration,
+    /// Where the embedded STUN server listens, if it is enabled.
+    pub stun_address: Option<SocketAddr>,
+    /// Validates the session descriptions and candidates in signaling messages.
+    pub validate_sdp: bool,
+    /// Session descriptions larger than this many bytes are rejected.
+    pub max_sdp_size: usize,
+    /// Strips host and private candidates, so peers never learn each other's local addresses.
+    pub strip_private_candidates: bool,
+    /// Offers crossing an unanswered offer sent the other way within this time are glare,
+    /// and resolved by the server. Zero disables glare resolution.
+    pub negotiation_window: Duration,
+    /// The secret HS256 tokens are signed with. Enables authentication.
+    pub jwt_secret: Option<String>,
+    /// The path to a PEM file with the public key RS256 or ES256 tokens are signed for.
+    /// Enables authentication.
+    pub jwt_public_key: Option<String>,
+    /// The claim of a token that holds the username.
+    pub jwt_username_claim: String,
+    /// The secret invites to private rooms are signed with.
+    /// A random secret is used unless configured, invalidating invites when the server restarts.
+    pub invite_secret: String,
+    /// How long invites to private rooms are valid.
+    pub invite_ttl: Duration,
+    /// The most users a room has a place for, unless given when the room is created.
+    pub room_capacity: Option<usize>,
+    /// The most keys the shared state of a room can hold.
+    pub room_state_size: usize,
+    /// Gives every origin a namespace of users and rooms of its own,
+    /// unless it is mapped to a tenant.
+    pub namespace_by_origin: bool,
+    /// Maps origins to the tenants whose namespace they share.
+    pub origin_tenants: HashMap<String, String>,
+    /// The JSON file listing the tenants nodes connect with an API key for, with their quotas.
+    pub tenants_file: Option<String>,
+    /// The origins web pages may connect from, e.g. `https://app.example.com` or `https://*.example.com`.
+    /// Any origin may connect if none are configured.
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            max_messages_per_second: None,
+            duplicate_usernames: DuplicatePolicy::default(),
+            resume_grace_period: None,
+            room_linger: Duration::from_secs(0),
+            queue_size: 32,
+            queue_total: 10_000,
+            queue_ttl: Duration::from_secs(300),
+            ring_timeout: Duration::from_secs(30),
+            ice_urls: Vec::new(),
+            turn_secret: None,
+            turn_ttl: Duration::from_secs(24 * 60 * 60),
+            stun_address: None,
+            validate_sdp: false,
+            max_sdp_size: 64 * 1024,
+            strip_private_candidates: false,
+            negotiation_window: Duration::from_secs(10),
+            jwt_secret: None,
+            jwt_public_key: None,
+            jwt_username_claim: "sub".to_string(),
+            invite_secret: generate_token(),
+            invite_ttl: Duration::from_secs(24 * 60 * 60),
+            room_capacity: None,
+            room_state_size: 64,
+            namespace_by_origin: false,
+            origin_tenants: HashMap::new(),
+            tenants_file: None,
+            allowed_origins: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// The command line flags used to configure the server.
+    pub fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+        vec![
+            Arg::with_name("max-messages-per-second")
+                .long("max-messages-per-second")
+                .value_name("COUNT")
+                .help("Rate limits each node to this many messages per second")
+                .validator(is_valid::<u32>)
+                .takes_value(true),
+            Arg::with_name("duplicate-usernames")
+                .long("duplicate-usernames")
+                .value_name("POLICY")
+                .help("What to do when a username is already connected")
+                .possible_values(&["reject-new", "replace-old", "auto-suffix"])
+                .takes_value(true),
+            Arg::with_name("resume-grace-period")
+                .long("resume-grace-period")
+                .value_name("SECONDS")
+                .help("Lets disconnected nodes resume their session within this many seconds")
+                .validator(is_valid::<u64>)
+                .takes_value(true),
+            Arg::with_name("room-linger")
+                .long("room-linger")
+                .value_name("SECONDS")
+                .help("Keeps empty rooms for this many seconds before removing them")
+                .validator(is_valid::<u64>)
+                .takes_value(true),
+            Arg::with_name("queue-size")
+                .long("queue-size")
+                .value_name("COUNT")
+                .help("Queues at most this many messages for an offline user [default: 32]")
+                .validator(is_valid::<usize>)
+                .takes_value(true),
+            Arg::with_name("queue-total")
+                .long("queue-total")
+                .value_name("COUNT")
+                .help("Queues at most this many messages for all offline users together [default: 10000]")
+                .validator(is_valid::<usize>)
+                .takes_value(true),
+            Arg::with_name("queue-ttl")
+                .long("queue-ttl")
+                .value_name("SECONDS")
+                .help("Drops queued messages after this many seconds [default: 300]")
+                .validator(is_valid::<u64>)
+                .takes_value(true),
+            Arg::with_name("ring-timeout")
+                .long("ring-timeout")
+                .value_name("SECONDS")
+                .help("Ends unanswered calls after this many seconds [default: 30]")
+                .validator(is_valid::<u64>)
+                .takes_value(true),
+            Arg::with_name("ice-url")
+                .long("ice-url")
+                .value_name("URL")
+                .help("A stun: or turn: URL handed to clients, may be repeated. Clients also fetch them from \
+                    GET /ice-servers, authenticated with a JWT, or without JWT authentication with the resume token \
+                    of their session, which requires --resume-grace-period")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+            Arg::with_name("turn-secret")
+                .long("turn-secret")
+                .value_name("SECRET")
+                .env("RUSTYSIGNAL_TURN_SECRET")
+                .help("The secret shared with the TURN server, used to create ephemeral credentials")
+                .takes_value(true),
+            Arg::with_name("turn-ttl")
+                .long("turn-ttl")
+                .value_name("SECONDS")
+                .help("How long TURN credentials are valid [default: 86400]")
+                .validator(is_valid::<u64>)
+                .takes_value(true),
+            Arg::with_name("stun-address")
+                .long("stun-address")
+                .value_name("ADDR")
+                .help("Answers STUN binding requests on this UDP address, e.g. 0.0.0.0:3478")
+                .validator(is_valid::<SocketAddr>)
+                .takes_value(true),
+            Arg::with_name("validate-sdp")
+                .long("validate-sdp")
+                .help("Rejects signaling messages with malformed session descriptions or candidates"),
+            Arg::with_name("max-sdp-size")
+                .long("max-sdp-size")
+                .value_name("BYTES")
+                .help("Rejects session descriptions larger than this when validating [default: 65536]")
+                .validator(is_valid::<usize>)
+                .takes_value(true),
+            Arg::with_name("strip-private-candidates")
+                .long("strip-private-candidates")
+                .help("Strips host and private ICE candidates from signaling messages (implies --validate-sdp)"),
+            Arg::with_name("negotiation-window")
+                .long("negotiation-window")
+                .value_name("SECONDS")
+                .help("Resolves offers that cross within this many seconds, 0 disables it [default: 10]")
+                .validator(is_valid::<u64>)
+                .takes_value(true),
+            Arg::with_name("jwt-secret")
+                .long("jwt-secret")
+                .value_name("SECRET")
+                .env("RUSTYSIGNAL_JWT_SECRET")
+                .help("Requires nodes to authenticate with a JWT signed with this secret (HS256)")
+                .takes_value(true),
+            Arg::with_name("jwt-public-key")
+                .long("jwt-public-key")
+                .value_name("PATH")
+                .help("Requires nodes to authenticate with a JWT signed for this PEM public key (RS256 or ES256)")
+                .takes_value(true),
+            Arg::with_name("jwt-username-claim")
+                .long("jwt-username-claim")
+                .value_name("CLAIM")
+                .help("The claim of the JWT that holds the username [default: sub]")
+                .takes_value(true),
+            Arg::with_name("invite-secret")
+                .long("invite-secret")
+                .value_name("SECRET")
+                .env("RUSTYSIGNAL_INVITE_SECRET")
+                .help("The secret invites to private rooms are signed with [default: random]")
+                .takes_value(true),
+            Arg::with_name("invite-ttl")
+                .long("invite-ttl")
+                .value_name("SECONDS")
+                .help("How long invites to private rooms are valid [default: 86400]")
+                .validator(is_valid::<u64>)
+                .takes_value(true),
+            Arg::with_name("room-capacity")
+                .long("room-capacity")
+                .value_name("COUNT")
+                .help("Limits rooms to this many users, unless another capacity is given when creating one")
+                .validator(is_valid::<usize>)
+                .takes_value(true),
+            Arg::with_name("room-state-size")
+                .long("room-state-size")
+                .value_name("COUNT")
+                .help("Limits the shared state of a room to this many keys [default: 64]")
+                .validator(is_valid::<usize>)
+                .takes_value(true),
+            Arg::with_name("namespace-by-origin")
+                .long("namespace-by-origin")
+                .help("Gives every origin its own users and rooms, unless it is mapped to a tenant"),
+            Arg::with_name("origin-tenant")
+                .long("origin-tenant")
+                .value_name("ORIGIN=TENANT")
+                .help("Puts the users and rooms of an origin in the namespace of a tenant, may be repeated")
+                .validator(|mapping| match mapping.split_once('=') {
+                    Some((origin, tenant)) if !origin.is_empty() && !tenant.is_empty() => Ok(()),
+                    _ => Err(format!("{:?} is not a mapping of an origin to a tenant, e.g. https://example.com=shop", mapping)),
+                })
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+            Arg::with_name("tenants-file")
+                .long("tenants-file")
+                .value_name("PATH")
+                .help("Requires nodes to connect with the API key of a tenant listed in this JSON file, \
+                    and enforces the tenant's quotas")
+                .takes_value(true),
+            Arg::with_name("allowed-origin")
+                .long("allowed-origin")
+                .value_name("ORIGIN")
+                .help("Only accepts handshakes from this origin, may be repeated. A single * matches any part \
+                    of the origin within its host or port, e.g. https://*.example.com, and a bare * matches any origin")
+                .validator(|origin| match origin.matches('*').count() {
+                    0 | 1 => Ok(()),
+                    _ => Err(format!("{:?} has more than one *, which is not supported", origin)),
+                })
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        ]
+    }
+
+    /// Reads the configuration from the command line flags.
+    /// Flags that are missing keep their default value.
+    pub fn from_matches(matches: &ArgMatches) -> Config {
+        let defaults = Config::default();
+        Config {
+            max_messages_per_second: parse(matches, "max-messages-per-second")
+                .or(defaults.max_messages_per_second),
+            duplicate_usernames: parse(matches, "duplicate-usernames")
+                .unwrap_or(defaults.duplicate_usernames),
+            resume_grace_period: parse(matches, "resume-grace-period")
+                .map(Duration::from_secs)
+                .or(defaults.resume_grace_period),
+            room_linger: parse(matches, "room-linger")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.room_linger),
+            queue_size: parse(matches, "queue-size")
+                .unwrap_or(defaults.queue_size),
+            queue_total: parse(matches, "queue-total")
+                .unwrap_or(defaults.queue_total),
+            queue_ttl: parse(matches, "queue-ttl")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.queue_ttl),
+            ring_timeout: parse(matches, "ring-timeout")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.ring_timeout),
+            ice_urls: matches.values_of_lossy("ice-url")
+                .unwrap_or(defaults.ice_urls),
+            turn_secret: matches.value_of("turn-secret")
+                .map(String::from)
+                .or(defaults.turn_secret),
+            turn_ttl: parse(matches, "turn-ttl")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.turn_ttl),
+            stun_address: parse(matches, "stun-address")
+                .or(defaults.stun_address),
+            validate_sdp: matches.is_present("validate-sdp")
+                || matches.is_present("strip-private-candidates")
+                || defaults.validate_sdp,
+            max_sdp_size: parse(matches, "max-sdp-size")
+                .unwrap_or(defaults.max_sdp_size),
+            strip_private_candidates: matches.is_present("strip-private-candidates")
+                || defaults.strip_private_candidates,
+            negotiation_window: parse(matches, "negotiation-window")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.negotiation_window),
+            jwt_secret: matches.value_of("jwt-secret")
+                .map(String::from)
+                .or(defaults.jwt_secret),
+            jwt_public_key: matches.value_of("jwt-public-key")
+                .map(String::from)
+                .or(defaults.jwt_public_key),
+            jwt_username_claim: matches.value_of("jwt-username-claim")
+                .map(String::from)
+                .unwrap_or(defaults.jwt_username_claim),
+            invite_secret: matches.value_of("invite-secret")
+                .map(String::from)
+                .unwrap_or(defaults.invite_secret),
+            invite_ttl: parse(matches, "invite-ttl")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.invite_ttl),
+            room_capacity: parse(matches, "room-capacity")
+                .or(defaults.room_capacity),
+            room_state_size: parse(matches, "room-state-size")
+                .unwrap_or(defaults.room_state_size),
+            namespace_by_origin: matches.is_present("namespace-by-origin")
+                || defaults.namespace_by_origin,
+            origin_tenants: matches.values_of("origin-tenant")
+                .map(|mappings| mappings
+                    .filter_map(|mapping| {
+                        let mut parts = mapping.splitn(2, '=');
+                        match (parts.next(), parts.next()) {
+                            (Some(origin), Some(tenant)) => Some((origin.to_ascii_lowercase(), tenant.to_string())),
+                            _ => None,
+                        }
+                    })
+                    .collect())
+                .unwrap_or(defaults.origin_tenants),
+            tenants_file: matches.value_of("tenants-file")
+                .map(String::from)
+                .or(defaults.tenants_file),
+            allowed_origins: matches.values_of("allowed-origin")
+                .map(|origins| origins.map(String::from).collect())
+                .unwrap_or(defaults.allowed_origins),
+        }
+    }
+
+    /// Checks if a handshake from the origin may be accepted.
+    /// Handshakes without an origin are not sent by browsers, so they are always accepted.
+    pub fn is_origin_allowed(&self, origin: Option<&str>) -> bool {
+        match origin {
+            Some(origin) if !self.allowed_origins.is_empty() => {
+                let origin = origin.to_ascii_lowercase();
+                self.allowed_origins.iter().any(|allowed| origin_matches(&allowed.to_ascii_lowercase(), &origin))
+            },
+            _ => true,
+        }
+    }
+
+    /// The tenant whose namespace of users and rooms a node connecting from the origin is put in.
+    /// Nodes that send no origin, such as native clients, are put in the default namespace.
+    /// Origins are compared without regard to case, as they are in the allowlist.
+    pub fn tenant_for_origin(&self, origin: Option<&str>) -> String {
+        match origin.map(str::to_ascii_lowercase) {
+            Some(origin) => match self.origin_tenants.get(&origin) {
+                Some(tenant) => tenant.clone(),
+                None if self.namespace_by_origin => origin,
+                None => DEFAULT_TENANT.to_string(),
+            },
+            None => DEFAULT_TENANT.to_string(),
+        }
+    }
+}
+
+fn parse<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    matches.value_of(name).and_then(|value| value.parse().ok())
+}
+
+/// Checks that a flag's value parses, so a mistyped value fails at startup instead of being ignored.
+fn is_valid<T: FromStr>(value: String) -> Result<(), String> where T::Err: Display {
+    value.parse::<T>()
+        .map(|_| ())
+        .map_err(|error| format!("{:?} is not valid: {}", value, error))
+}
+
+/// Matches an origin against an allowed origin, where a bare `*` matches any origin,
+/// and otherwise a single `*` matches any part of the origin that does not cross a `/`,
+/// e.g. the subdomain in `https://*.example.com`.
+fn origin_matches(allowed: &str, origin: &str) -> bool {
+    if allowed == "*" {
+        return true;
+    }
+    let mut parts = allowed.splitn(2, '*');
+    match (parts.next(), parts.next()) {
+        (Some(prefix), Some(suffix)) => {
+            origin.len() > prefix.len() + suffix.len()
+                && origin.starts_with(prefix)
+                && origin.ends_with(suffix)
+                && !origin[prefix.len()..origin.len() - suffix.len()].contains('/')
+        },
+        _ => allowed == origin,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::App;
+
+    /// Reads the configuration from the flags, as they would be given on the command line.
+    fn parse(flags: &[&str]) -> Result<Config, clap::Error> {
+        let matches = App::new("test").args(&Config::args())
+            .get_matches_from_safe(std::iter::once("test").chain(flags.iter().cloned()))?;
+        Ok(Config::from_matches(&matches))
+    }
+
+    #[test]
+    fn any_origin_is_allowed_by_default() {
+        let config = Config::default();
+        assert!(config.is_origin_allowed(Some("https://example.com")));
+        assert!(config.is_origin_allowed(None));
+    }
+
+    #[test]
+    fn only_allowed_origins_are_allowed() {
+        let config = parse(&["--allowed-origin", "https://example.com", "--allowed-origin", "https://*.Example.org"]).unwrap();
+        assert!(config.is_origin_allowed(Some("https://example.com")));
+        assert!(config.is_origin_allowed(Some("HTTPS://EXAMPLE.COM")));
+        assert!(config.is_origin_allowed(Some("https://chat.example.org")));
+        assert!(config.is_origin_allowed(Some("https://a.b.example.org")));
+        assert!(!config.is_origin_allowed(Some("https://example.com:8080")));
+        assert!(!config.is_origin_allowed(Some("https://example.org")));
+        assert!(!config.is_origin_allowed(Some("https://evil.com/.example.org")));
+        assert!(!config.is_origin_allowed(Some("http://chat.example.org")));
+    }
+
+    #[test]
+    fn wildcards_match_within_the_host_or_port() {
+        assert!(origin_matches("*", "https://example.com"));
+        assert!(origin_matches("*", "null"));
+        assert!(origin_matches("https://example.com:*", "https://example.com:8080"));
+        assert!(!origin_matches("https://example.com:*", "https://example.com:"));
+        assert!(!origin_matches("https://*", "https://example.com/path"));
+        assert!(!origin_matches("https://example.com", "https://example.co"));
+    }
+
+    #[test]
+    fn rejects_patterns_with_several_wildcards() {
+        assert!(parse(&["--allowed-origin", "https://*.*.example.com"]).is_err());
+        assert!(parse(&["--allowed-origin", "**"]).is_err());
+        assert!(parse(&["--allowed-origin", "*"]).is_ok());
+    }
+
+    #[test]
+    fn origins_share_the_default_tenant_unless_namespaced() {
+        let config = Config::default();
+        assert_eq!(config.tenant_for_origin(Some("https://example.com")), DEFAULT_TENANT);
+        assert_eq!(config.tenant_for_origin(None), DEFAULT_TENANT);
+
+        let config = parse(&["--namespace-by-origin"]).unwrap();
+        assert_eq!(config.tenant_for_origin(Some("https://example.com")), "https://example.com");
+        assert_eq!(config.tenant_for_origin(Some("https://Example.com")), "https://example.com");
+        assert_eq!(config.tenant_for_origin(None), DEFAULT_TENANT);
+    }
+
+    #[test]
+    fn origins_are_mapped_to_their_tenants() {
+        let config = parse(&["--origin-tenant", "https://example.com=shop", "--origin-tenant", "https://Example.org=shop"]).unwrap();
+        assert_eq!(config.tenant_for_origin(Some("https://example.com")), "shop");
+        assert_eq!(config.tenant_for_origin(Some("HTTPS://EXAMPLE.COM")), "shop");
+        assert_eq!(config.tenant_for_origin(Some("https://example.org")), "shop");
+        assert_eq!(config.tenant_for_origin(Some("https://example.net")), DEFAULT_TENANT);
+    }
+
+    #[test]
+    fn rejects_malformed_origin_tenants() {
+        assert!(parse(&["--origin-tenant", "https://example.net"]).is_err());
+        assert!(parse(&["--origin-tenant", "=shop"]).is_err());
+        assert!(parse(&["--origin-tenant", "https://example.net="]).is_err());
+    }
+
+    #[test]
+    fn reads_the_flags() {
+        let config = parse(&["--room-capacity", "8", "--resume-grace-period", "30", "--stun-address", "0.0.0.0:3478"]).unwrap();
+        assert_eq!(config.room_capacity, Some(8));
+        assert_eq!(config.resume_grace_period, Some(Duration::from_secs(30)));
+        assert_eq!(config.stun_address, "0.0.0.0:3478".parse().ok());
+        assert_eq!(config.max_messages_per_second, None);
+    }
+
+    #[test]
+    fn rejects_flags_that_do_not_parse() {
+        assert!(parse(&["--room-capacity", "abc"]).is_err());
+        assert!(parse(&["--resume-grace-period", "30s"]).is_err());
+        assert!(parse(&["--max-messages-per-second", "-1"]).is_err());
+        assert!(parse(&["--stun-address", "0.0.0.0"]).is_err());
+        assert!(parse(&["--queue-ttl", ""]).is_err());
+    }
+}
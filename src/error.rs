@@ -0,0 +1,272 @@
+//! Errors that are reported back to a node.
+//! Every error has a stable, machine readable code, so clients can
+//! tell an error frame apart from a relayed message and react to it.
+
+use std::fmt;
+
+use message::Delivery;
+
+pub type SignalResult = Result<(), SignalError>;
+pub type DeliveryResult = Result<Delivery, SignalError>;
+
+/// Close code sent to a node whose username was taken over by a new connection.
+pub const CLOSE_REPLACED: u16 = 4001;
+/// Close code sent to a node that connected with a username that is already in use.
+pub const CLOSE_USERNAME_TAKEN: u16 = 4009;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignalError {
+    /// The query string of the handshake could not be understood.
+    BadHandshake(String),
+    /// The message was not valid JSON, or did not fit the envelope.
+    MalformedMessage(String),
+    /// A signaling message did not say how it should be relayed.
+    MissingProtocol,
+    /// The message lacks a field that is required by its type or protocol.
+    MissingField(&'static str),
+    /// No node with that username is connected.
+    UserNotFound(String),
+    /// The node with that username could not receive the message.
+    PeerOffline(String),
+    /// Too many messages are already queued for the offline user.
+    QueueFull(String),
+    /// No room with that name exists.
+    RoomNotFound(String),
+    /// The node is not a member of the room.
+    NotInRoom(String),
+    /// The room is private, and the node gave no valid password or invite.
+    RoomAccessDenied(String),
+    /// The user may not manage the room.
+    NotPermitted(String),
+    /// The room is not private, so there is no need for an invite.
+    RoomNotPrivate(String),
+    /// The shared state of the room has no room for another key.
+    RoomStateFull(String),
+    /// A field of the message is larger than allowed.
+    TooLarge(&'static str),
+    /// The room has no place for another user.
+    RoomFull(String),
+    /// The user is banned from the room.
+    Banned(String),
+    /// The user is muted in the room.
+    Muted(String),
+    /// The node sent more messages than it is allowed to.
+    RateLimited,
+    /// The tenant of the node has used up its quota of connections, rooms or messages.
+    QuotaExceeded(&'static str),
+    /// Another node is already connected with that username.
+    UsernameTaken(String),
+    /// No ringing call with that id involves the user.
+    CallNotFound(String),
+    /// The user tried to call itself.
+    CallingSelf,
+    /// The request could not be authenticated.
+    Unauthorized,
+    /// Handshakes from the origin are not accepted.
+    OriginNotAllowed(String),
+    /// A session description or candidate in the message is malformed or too large.
+    InvalidSdp(String),
+    /// The request requires the node to have a username.
+    Anonymous,
+    /// The user has not subscribed to push notifications.
+    #[cfg(feature = "push")]
+    NoPushSubscription(String),
+    /// The push notification could not be built or sent.
+    #[cfg(feature = "push")]
+    PushFailed(String),
+}
+
+impl SignalError {
+    /// The stable code sent to clients, which never changes with the wording of the message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            SignalError::BadHandshake(_) => "bad-handshake",
+            SignalError::MalformedMessage(_) => "malformed-message",
+            SignalError::MissingProtocol => "missing-protocol",
+            SignalError::MissingField(_) => "missing-field",
+            SignalError::UserNotFound(_) => "user-not-found",
+            SignalError::PeerOffline(_) => "peer-offline",
+            SignalError::QueueFull(_) => "queue-full",
+            SignalError::RoomNotFound(_) => "room-not-found",
+            SignalError::NotInRoom(_) => "not-in-room",
+            SignalError::RoomAccessDenied(_) => "room-access-denied",
+            SignalError::NotPermitted(_) => "not-permitted",
+            SignalError::RoomNotPrivate(_) => "room-not-private",
+            SignalError::RoomStateFull(_) => "room-state-full",
+            SignalError::TooLarge(_) => "too-large",
+            SignalError::RoomFull(_) => "room-full",
+            SignalError::Banned(_) => "banned",
+            SignalError::Muted(_) => "muted",
+            SignalError::RateLimited => "rate-limited",
+            SignalError::QuotaExceeded(_) => "quota-exceeded",
+            SignalError::UsernameTaken(_) => "username-taken",
+            SignalError::CallNotFound(_) => "call-not-found",
+            SignalError::CallingSelf => "calling-self",
+            SignalError::Unauthorized => "unauthorized",
+            SignalError::OriginNotAllowed(_) => "origin-not-allowed",
+            SignalError::InvalidSdp(_) => "invalid-sdp",
+            SignalError::Anonymous => "anonymous",
+            #[cfg(feature = "push")]
+            SignalError::NoPushSubscription(_) => "no-push-subscription",
+            #[cfg(feature = "push")]
+            SignalError::PushFailed(_) => "push-failed",
+        }
+    }
+
+    /// The reason sent in a nack if the error means a message could not be delivered.
+    pub fn nack_reason(&self) -> Option<&'static str> {
+        match self {
+            SignalError::UserNotFound(_) | SignalError::PeerOffline(_) => Some("peer-offline"),
+            SignalError::QueueFull(_) => Some("queue-full"),
+            SignalError::RoomNotFound(_) => Some("room-not-found"),
+            SignalError::RateLimited => Some("rate-limited"),
+            SignalError::QuotaExceeded(_) => Some("quota-exceeded"),
+            _ => None,
+        }
+    }
+
+    /// Builds the error frame sent to the client.
+    /// The reference is the id of the message that caused the error, if the client provided one.
+    pub fn to_frame(&self, reference: Option<&str>) -> String {
+        json!({
+            "type": "error",
+            "code": self.code(),
+            "message": self.to_string(),
+            "ref": reference,
+        }).to_string()
+    }
+}
+
+impl fmt::Display for SignalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignalError::BadHandshake(reason) =>
+                write!(f, "Bad handshake: {}", reason),
+            SignalError::MalformedMessage(reason) =>
+                write!(f, "Malformed message: {}", reason),
+            SignalError::MissingProtocol =>
+                write!(f, "No protocol provided, valid protocols include: \
+                    'one-to-self', 'one-to-one', 'one-to-room', 'one-to-all'"),
+            SignalError::MissingField(field) =>
+                write!(f, "No field '{}' provided", field),
+            SignalError::UserNotFound(user) =>
+                write!(f, "Could not find a node with the name {:?}", user),
+            SignalError::PeerOffline(user) =>
+                write!(f, "The message could not be delivered to {:?}", user),
+            SignalError::QueueFull(user) =>
+                write!(f, "Too many messages are queued for {:?}", user),
+            SignalError::RoomNotFound(room) =>
+                write!(f, "Could not find a room with the name {:?}", room),
+            SignalError::NotInRoom(room) =>
+                write!(f, "Not a member of the room {:?}", room),
+            SignalError::RoomAccessDenied(room) =>
+                write!(f, "A valid password or invite is required to enter the room {:?}", room),
+            SignalError::NotPermitted(room) =>
+                write!(f, "Not permitted to manage the room {:?}", room),
+            SignalError::RoomNotPrivate(room) =>
+                write!(f, "The room {:?} is not private, anyone can enter it without an invite", room),
+            SignalError::RoomStateFull(room) =>
+                write!(f, "The shared state of the room {:?} has too many keys", room),
+            SignalError::TooLarge(field) =>
+                write!(f, "The field '{}' is too large", field),
+            SignalError::RoomFull(room) =>
+                write!(f, "The room {:?} is full", room),
+            SignalError::Banned(room) =>
+                write!(f, "Banned from the room {:?}", room),
+            SignalError::Muted(room) =>
+                write!(f, "Muted in the room {:?}", room),
+            SignalError::RateLimited =>
+                write!(f, "Too many messages, slow down"),
+            SignalError::QuotaExceeded(quota) =>
+                write!(f, "The tenant has used up its quota of {}", quota),
+            SignalError::UsernameTaken(user) =>
+                write!(f, "The username {:?} is taken", user),
+            SignalError::CallNotFound(call) =>
+                write!(f, "Could not find a ringing call with the id {:?}", call),
+            SignalError::CallingSelf =>
+                write!(f, "A user can not call itself"),
+            SignalError::Unauthorized =>
+                write!(f, "The request could not be authenticated"),
+            SignalError::OriginNotAllowed(origin) =>
+                write!(f, "Handshakes from the origin {:?} are not accepted", origin),
+            SignalError::InvalidSdp(reason) =>
+                write!(f, "Invalid session description: {}", reason),
+            SignalError::Anonymous =>
+                write!(f, "A username is required for this request"),
+            #[cfg(feature = "push")]
+            SignalError::NoPushSubscription(user) =>
+                write!(f, "{:?} has not subscribed to push notifications", user),
+            #[cfg(feature = "push")]
+            SignalError::PushFailed(reason) =>
+                write!(f, "Could not send the push notification: {}", reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_stable() {
+        let codes = [
+            (SignalError::BadHandshake("reason".to_string()), "bad-handshake"),
+            (SignalError::MalformedMessage("reason".to_string()), "malformed-message"),
+            (SignalError::MissingProtocol, "missing-protocol"),
+            (SignalError::MissingField("to"), "missing-field"),
+            (SignalError::UserNotFound("bob".to_string()), "user-not-found"),
+            (SignalError::PeerOffline("bob".to_string()), "peer-offline"),
+            (SignalError::QueueFull("bob".to_string()), "queue-full"),
+            (SignalError::RoomNotFound("lobby".to_string()), "room-not-found"),
+            (SignalError::NotInRoom("lobby".to_string()), "not-in-room"),
+            (SignalError::RoomAccessDenied("lobby".to_string()), "room-access-denied"),
+            (SignalError::NotPermitted("lobby".to_string()), "not-permitted"),
+            (SignalError::RoomNotPrivate("lobby".to_string()), "room-not-private"),
+            (SignalError::RoomStateFull("lobby".to_string()), "room-state-full"),
+            (SignalError::TooLarge("topic"), "too-large"),
+            (SignalError::RoomFull("lobby".to_string()), "room-full"),
+            (SignalError::Banned("lobby".to_string()), "banned"),
+            (SignalError::Muted("lobby".to_string()), "muted"),
+            (SignalError::RateLimited, "rate-limited"),
+            (SignalError::QuotaExceeded("rooms"), "quota-exceeded"),
+            (SignalError::UsernameTaken("alice".to_string()), "username-taken"),
+            (SignalError::CallNotFound("call".to_string()), "call-not-found"),
+            (SignalError::CallingSelf, "calling-self"),
+            (SignalError::Unauthorized, "unauthorized"),
+            (SignalError::OriginNotAllowed("https://example.com".to_string()), "origin-not-allowed"),
+            (SignalError::InvalidSdp("reason".to_string()), "invalid-sdp"),
+            (SignalError::Anonymous, "anonymous"),
+        ];
+        for (error, code) in codes.iter() {
+            assert_eq!(error.code(), *code);
+        }
+    }
+
+    #[test]
+    fn only_undelivered_messages_are_nacked() {
+        assert_eq!(SignalError::UserNotFound("bob".to_string()).nack_reason(), Some("peer-offline"));
+        assert_eq!(SignalError::PeerOffline("bob".to_string()).nack_reason(), Some("peer-offline"));
+        assert_eq!(SignalError::QueueFull("bob".to_string()).nack_reason(), Some("queue-full"));
+        assert_eq!(SignalError::RoomNotFound("lobby".to_string()).nack_reason(), Some("room-not-found"));
+        assert_eq!(SignalError::RateLimited.nack_reason(), Some("rate-limited"));
+        assert_eq!(SignalError::QuotaExceeded("messages per second").nack_reason(), Some("quota-exceeded"));
+
+        assert_eq!(SignalError::MissingProtocol.nack_reason(), None);
+        assert_eq!(SignalError::NotInRoom("lobby".to_string()).nack_reason(), None);
+        assert_eq!(SignalError::Banned("lobby".to_string()).nack_reason(), None);
+    }
+
+    #[test]
+    fn builds_error_frames() {
+        let frame: serde_json::Value = serde_json::from_str(&SignalError::RoomFull("lobby".to_string()).to_frame(Some("m1"))).unwrap();
+        assert_eq!(frame, json!({
+            "type": "error",
+            "code": "room-full",
+            "message": "The room \"lobby\" is full",
+            "ref": "m1",
+        }));
+
+        let frame: serde_json::Value = serde_json::from_str(&SignalError::RateLimited.to_frame(None)).unwrap();
+        assert_eq!(frame["ref"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,34 @@
+//! Messages waiting for an offline user to connect.
+//! A `one-to-one` message sent with `"queue": true` to a user that is not connected
+//! is kept for a while, and delivered as soon as the user connects.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// The queued messages of each username, oldest first.
+pub type Mailboxes = HashMap<String, VecDeque<QueuedMessage>>;
+
+pub struct QueuedMessage {
+    /// The relayed message, exactly as it will be sent.
+    pub text: String,
+    /// The user that sent the message, who gets a receipt once it is delivered.
+    pub from: Option<String>,
+    /// The id the sender gave the message, used in the receipt.
+    pub id: Option<String>,
+    pub expires_at: Instant,
+}
+
+impl QueuedMessage {
+    pub fn new(text: String, from: Option<String>, id: Option<String>, ttl: Duration) -> QueuedMessage {
+        QueuedMessage {
+            text,
+            from,
+            id,
+            expires_at: Instant::now() + ttl,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.expires_at
+    }
+}
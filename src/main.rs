@@ -1,3 +1,6 @@
+// Handlers return the error type of ws, which we cannot make any smaller
+#![allow(clippy::result_large_err)]
+
 extern crate ws;
 #[macro_use]
 extern crate serde_json;
@@ -6,11 +9,12 @@ extern crate clap;
 extern crate env_logger;
 
 extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate tokio;
 extern crate base64;
 extern crate futures;
 
-#[cfg(feature = "ssl")]
 extern crate openssl;
 #[cfg(feature = "push")]
 extern crate web_push;
@@ -21,6 +25,20 @@ mod node;
 mod network;
 
 mod room;
+mod message;
+mod error;
+mod config;
+mod query;
+mod session;
+mod mailbox;
+mod call;
+mod turn;
+mod stun;
+mod sdp;
+mod negotiation;
+mod auth;
+mod access;
+mod tenant;
 
 fn main() {
     server::run()
@@ -0,0 +1,398 @@
+//! The message envelope exchanged between a node and the signaling server.
+//! Every text frame a client sends is parsed once into an `Envelope`,
+//! and every frame the server relays is serialized from one.
+//! Fields the server does not know about are kept in `extra`,
+//! so they are relayed to the receiving peers untouched.
+
+use serde_json::{Map, Value};
+
+use access::Invite;
+use call::{Call, CallState};
+use negotiation::Role;
+use room::{Moderation, Room};
+use session::Session;
+
+/// What kind of request a message is.
+/// Messages that do not specify a type are plain signaling messages,
+/// which are relayed to other nodes according to their protocol.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MessageType {
+    #[default]
+    Signal,
+    JoinRoom,
+    LeaveRoom,
+    Watch,
+    Unwatch,
+    SetStatus,
+    Call,
+    AcceptCall,
+    RejectCall,
+    CancelCall,
+    GetIceServers,
+    CreateInvite,
+    RevokeInvite,
+    UpdateRoom,
+    GetState,
+    SetState,
+    DeleteState,
+    ListRooms,
+    Kick,
+    Ban,
+    Unban,
+    Mute,
+    Unmute,
+    Promote,
+    Demote,
+    TransferOwnership,
+}
+
+impl MessageType {
+    /// The moderation command a message gives, if any.
+    pub fn moderation(self) -> Option<Moderation> {
+        match self {
+            MessageType::Kick => Some(Moderation::Kick),
+            MessageType::Ban => Some(Moderation::Ban),
+            MessageType::Unban => Some(Moderation::Unban),
+            MessageType::Mute => Some(Moderation::Mute),
+            MessageType::Unmute => Some(Moderation::Unmute),
+            MessageType::Promote => Some(Moderation::Promote),
+            MessageType::Demote => Some(Moderation::Demote),
+            MessageType::TransferOwnership => Some(Moderation::TransferOwnership),
+            _ => None,
+        }
+    }
+}
+
+/// Decides which nodes a signaling message is relayed to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[allow(clippy::enum_variant_names)]
+pub enum Protocol {
+    OneToSelf,
+    OneToOne,
+    OneToRoom,
+    OneToAll,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Envelope {
+    #[serde(rename = "type", default)]
+    pub kind: MessageType,
+    /// Identifies the message in the acknowledgement sent back to the client.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<Protocol>,
+    /// The receiving user of a `one-to-one` message.
+    /// Older clients call this field `endpoint`.
+    #[serde(alias = "endpoint", skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    /// The password of a private room to join, or to create the room with.
+    /// It is never relayed.
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+    /// The invite to join a private room with, or the invite to revoke.
+    #[serde(skip_serializing)]
+    pub invite: Option<String>,
+    /// Creates the room as invite only, if it does not exist yet.
+    #[serde(default, skip_serializing)]
+    pub private: bool,
+    /// Creates the room with a place for this many users, if it does not exist yet.
+    #[serde(skip_serializing)]
+    pub capacity: Option<usize>,
+    /// Waits for a place if the room to join is full, instead of failing.
+    #[serde(default, skip_serializing)]
+    pub wait: bool,
+    /// The new topic of a room.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// The new metadata of a room.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+    /// Hides the room from the room list, or shows it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hidden: Option<bool>,
+    /// Lists only the rooms whose names start with this.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    /// Lists the rooms after the room with this name, to get the next page of the list.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<String>,
+    /// Lists at most this many rooms.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    /// The key of the shared state of a room to get, set or delete.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// The value to set a key of the shared state of a room to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<Value>,
+    /// The id of the call to accept, reject or cancel.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub call: Option<String>,
+    /// The users to start or stop watching the presence of.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<String>,
+    /// Asks the server to queue a `one-to-one` message if the receiving user is offline.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub queue: bool,
+    /// A custom status such as away or busy, cleared if missing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// The user that sent the message.
+    /// This is always set by the server, whatever the client claims.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub payload: Value,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+impl Envelope {
+    /// Parses a text frame sent by a client.
+    pub fn parse(text: &str) -> Result<Envelope, serde_json::Error> {
+        serde_json::from_str(text)
+    }
+
+    /// The client supplied id of the message, used to refer back to it.
+    pub fn reference(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Serializes the envelope to a text frame that can be relayed.
+    pub fn to_text(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+
+/// Builds the first frame a node receives, telling it the username it was registered with,
+/// and the token it can resume its session with after losing the connection.
+pub fn welcome_frame(session: &Session) -> String {
+    json!({
+        "type": "welcome",
+        "user": session.owner,
+        "resumeToken": session.resume_token,
+        "resumed": session.resumed,
+    }).to_string()
+}
+
+/// Builds a frame telling a node that joined a room who else is in it,
+/// and what the room is about.
+pub fn member_list_frame(room: &Room, members: &[String]) -> String {
+    json!({
+        "type": "member-list",
+        "room": room.name,
+        "members": members,
+        "topic": *room.topic.borrow(),
+        "metadata": *room.metadata.borrow(),
+        "state": *room.state.borrow(),
+    }).to_string()
+}
+
+/// Builds a frame with a page of the room list.
+/// The next page is listed by asking for the rooms after `next`, which is missing on the last page.
+pub fn room_list_frame(rooms: &[&Room], next: Option<&str>, reference: Option<&str>) -> String {
+    let rooms: Vec<Value> = rooms.iter().map(|room| json!({
+        "name": room.name,
+        "members": room.member_count(),
+        "capacity": room.capacity,
+        "private": room.is_private(),
+        "topic": *room.topic.borrow(),
+        "metadata": *room.metadata.borrow(),
+    })).collect();
+    json!({"type": "room-list", "rooms": rooms, "next": next, "ref": reference}).to_string()
+}
+
+/// Builds a frame telling the members of a room that its topic or metadata changed.
+pub fn room_updated_frame(room: &Room, by: &str) -> String {
+    json!({
+        "type": "room-updated",
+        "room": room.name,
+        "topic": *room.topic.borrow(),
+        "metadata": *room.metadata.borrow(),
+        "hidden": room.hidden.get(),
+        "by": by,
+    }).to_string()
+}
+
+/// Builds a frame with the shared state of a room, or a single key of it.
+pub fn room_state_frame(room: &Room, key: Option<&String>, reference: Option<&str>) -> String {
+    let state = room.state.borrow();
+    match key {
+        Some(key) => json!({"type": "room-state", "room": room.name, "key": key, "value": state.get(key), "ref": reference}),
+        None => json!({"type": "room-state", "room": room.name, "state": *state, "ref": reference}),
+    }.to_string()
+}
+
+/// Builds a frame telling the members of a room that a key of the shared state was set,
+/// or deleted if there is no value.
+pub fn state_changed_frame(room: &str, key: &str, value: Option<&Value>, by: &str) -> String {
+    json!({"type": "state-changed", "room": room, "key": key, "value": value, "by": by}).to_string()
+}
+
+/// Builds a frame telling the members of a room about a moderation command given in it.
+pub fn moderation_frame(room: &str, action: Moderation, user: &str, by: &str) -> String {
+    json!({"type": "moderation", "room": room, "action": action.as_str(), "user": user, "by": by}).to_string()
+}
+
+/// Builds a frame with an invite to a private room, for the moderators to hand out.
+pub fn invite_frame(room: &str, invite: &Invite, reference: Option<&str>) -> String {
+    json!({
+        "type": "invite",
+        "room": room,
+        "id": invite.id,
+        "invite": invite.token,
+        "expiresAt": invite.expires_at,
+        "ref": reference,
+    }).to_string()
+}
+
+/// Builds a frame telling a node that the room is full, and where it is on the waiting list.
+pub fn waiting_frame(room: &str, position: usize) -> String {
+    json!({"type": "waiting", "room": room, "position": position}).to_string()
+}
+
+/// Builds a frame telling the members of a room that a user joined.
+pub fn member_joined_frame(room: &str, user: &str) -> String {
+    json!({"type": "member-joined", "room": room, "user": user}).to_string()
+}
+
+/// Builds a frame telling the members of a room that a user left.
+pub fn member_left_frame(room: &str, user: &str) -> String {
+    json!({"type": "member-left", "room": room, "user": user}).to_string()
+}
+
+/// Builds a frame telling a watcher whether a user is online, and its custom status.
+pub fn presence_frame(user: &str, online: bool, status: Option<&String>) -> String {
+    json!({"type": "presence", "user": user, "online": online, "status": status}).to_string()
+}
+
+/// Builds a frame telling a user that its offer crossed an offer from the peer,
+/// and which role it takes in resolving it.
+pub fn glare_frame(peer: &str, role: Role) -> String {
+    json!({"type": "glare", "peer": peer, "role": role.as_str()}).to_string()
+}
+
+/// Builds a frame with the ICE servers and TURN credentials a node asked for.
+pub fn ice_servers_frame(ice_servers: Value, reference: Option<&str>) -> String {
+    let mut frame = ice_servers;
+    frame["type"] = json!("ice-servers");
+    frame["ref"] = json!(reference);
+    frame.to_string()
+}
+
+/// Builds a frame ringing the callee of a call.
+pub fn ring_frame(call: &Call) -> String {
+    json!({"type": "ring", "call": call.id, "from": call.caller, "payload": call.payload}).to_string()
+}
+
+/// Builds a frame telling the caller and callee that the state of a call changed.
+pub fn call_state_frame(call: &Call, state: CallState) -> String {
+    json!({
+        "type": "call-state",
+        "call": call.id,
+        "state": state.as_str(),
+        "caller": call.caller,
+        "callee": call.callee,
+    }).to_string()
+}
+
+/// What happened to a message that was handled successfully.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delivery {
+    Delivered,
+    /// The message waits for the offline receiver to connect.
+    Queued,
+    /// The message only carried candidates that were stripped, so it was not relayed.
+    Filtered,
+    /// The offer crossed an offer from the receiver, which won, so it was not relayed.
+    Superseded,
+}
+
+impl Delivery {
+    pub fn status(self) -> &'static str {
+        match self {
+            Delivery::Delivered => "delivered",
+            Delivery::Queued => "queued",
+            Delivery::Filtered => "filtered",
+            Delivery::Superseded => "superseded",
+        }
+    }
+}
+
+/// Builds a frame telling the client what happened to the message with the id.
+pub fn ack_frame(id: &str, delivery: Delivery) -> String {
+    json!({"type": "ack", "id": id, "status": delivery.status()}).to_string()
+}
+
+/// Builds a frame telling the client that the message with the id was not delivered.
+pub fn nack_frame(id: &str, reason: &str) -> String {
+    json!({"type": "nack", "id": id, "reason": reason}).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_signaling_message() {
+        let envelope = Envelope::parse(r#"{"protocol": "one-to-one", "endpoint": "bob", "id": "1", "payload": {"sdp": "v=0"}}"#)
+            .unwrap();
+        assert_eq!(envelope.kind, MessageType::Signal);
+        assert_eq!(envelope.protocol, Some(Protocol::OneToOne));
+        assert_eq!(envelope.to.as_deref(), Some("bob"));
+        assert_eq!(envelope.reference(), Some("1"));
+        assert_eq!(envelope.payload["sdp"], "v=0");
+    }
+
+    #[test]
+    fn parses_a_request_type() {
+        let envelope = Envelope::parse(r#"{"type": "transfer-ownership", "room": "lobby", "users": ["bob"]}"#).unwrap();
+        assert_eq!(envelope.kind, MessageType::TransferOwnership);
+        assert_eq!(envelope.kind.moderation(), Some(Moderation::TransferOwnership));
+        assert_eq!(envelope.users, vec!["bob".to_string()]);
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        assert!(Envelope::parse("not json").is_err());
+        assert!(Envelope::parse(r#"{"type": "no-such-type"}"#).is_err());
+        assert!(Envelope::parse(r#"{"protocol": "one-to-many"}"#).is_err());
+        assert!(Envelope::parse(r#"["a", "list"]"#).is_err());
+    }
+
+    #[test]
+    fn relays_unknown_fields_untouched() {
+        let envelope = Envelope::parse(r#"{"protocol": "one-to-all", "custom": {"nested": [1, 2]}}"#).unwrap();
+        let relayed: Value = serde_json::from_str(&envelope.to_text()).unwrap();
+        assert_eq!(relayed["custom"], json!({"nested": [1, 2]}));
+    }
+
+    #[test]
+    fn never_relays_secrets() {
+        let envelope = Envelope::parse(r#"{"type": "join-room", "room": "vault", "password": "hunter2",
+            "invite": "abc.1.sig", "private": true, "capacity": 4, "wait": true}"#).unwrap();
+        assert_eq!(envelope.password.as_deref(), Some("hunter2"));
+        let relayed = envelope.to_text();
+        assert!(!relayed.contains("hunter2"));
+        assert!(!relayed.contains("abc.1.sig"));
+        assert!(!relayed.contains("capacity"));
+    }
+
+    #[test]
+    fn builds_acks_and_nacks() {
+        let ack: Value = serde_json::from_str(&ack_frame("7", Delivery::Queued)).unwrap();
+        assert_eq!(ack, json!({"type": "ack", "id": "7", "status": "queued"}));
+        let nack: Value = serde_json::from_str(&nack_frame("7", "peer-offline")).unwrap();
+        assert_eq!(nack, json!({"type": "nack", "id": "7", "reason": "peer-offline"}));
+    }
+}
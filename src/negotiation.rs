@@ -0,0 +1,124 @@
+//! Glare resolution for `one-to-one` offers that cross each other.
+//! When two users send each other an offer at the same time, both end up
+//! waiting for an answer that never comes. The server remembers the unanswered
+//! offers between each pair of users, and when one crosses an offer sent the other way
+//! within the negotiation window, it assigns the users the roles of perfect negotiation:
+//! the impolite user keeps its offer and ignores the other one,
+//! while the polite user rolls back its offer and answers the other one.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use message::Envelope;
+
+/// The role of a user in a negotiation with a peer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    /// Rolls back its own offer when offers cross.
+    Polite,
+    /// Ignores the offer of its peer when offers cross.
+    Impolite,
+}
+
+impl Role {
+    /// Assigns the role of a user negotiating with a peer.
+    /// The user whose name sorts first is impolite, so both users always agree on their roles.
+    pub fn of(user: &str, peer: &str) -> Role {
+        if user < peer {
+            Role::Impolite
+        } else {
+            Role::Polite
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Polite => "polite",
+            Role::Impolite => "impolite",
+        }
+    }
+}
+
+/// The kind of session description a signaling message carries, if any,
+/// e.g. `offer` for `{"payload": {"type": "offer", "sdp": ...}}`.
+pub fn description_type(envelope: &Envelope) -> Option<&str> {
+    let payload = &envelope.payload;
+    let kind = payload.get("type")
+        .or_else(|| payload.pointer("/description/type"))
+        .and_then(Value::as_str);
+
+    kind.or_else(|| ["offer", "answer"].iter()
+        .find(|kind| envelope.extra.contains_key(**kind))
+        .cloned())
+}
+
+/// The offers that have not been answered yet, keyed on the sender and the receiver.
+#[derive(Default)]
+pub struct PendingOffers {
+    offers: HashMap<(String, String), Instant>,
+}
+
+impl PendingOffers {
+    /// Records an offer, and checks if it crosses an unanswered offer
+    /// the receiver sent within the negotiation window.
+    pub fn offer(&mut self, from: &str, to: &str, window: Duration) -> bool {
+        self.offers.retain(|_, sent_at| sent_at.elapsed() < window);
+        let crossed = self.offers.contains_key(&(to.to_string(), from.to_string()));
+        self.offers.insert((from.to_string(), to.to_string()), Instant::now());
+        crossed
+    }
+
+    /// Forgets the offer from one user to another,
+    /// because it was answered, rolled back or lost the glare.
+    pub fn forget(&mut self, from: &str, to: &str) {
+        self.offers.remove(&(from.to_string(), to.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn peers_agree_on_their_roles() {
+        assert_eq!(Role::of("alice", "bob"), Role::Impolite);
+        assert_eq!(Role::of("bob", "alice"), Role::Polite);
+    }
+
+    #[test]
+    fn finds_the_description_type() {
+        let envelope = |text| Envelope::parse(text).unwrap();
+        assert_eq!(description_type(&envelope(r#"{"payload": {"type": "offer", "sdp": "v=0"}}"#)), Some("offer"));
+        assert_eq!(description_type(&envelope(r#"{"payload": {"description": {"type": "answer"}}}"#)), Some("answer"));
+        assert_eq!(description_type(&envelope(r#"{"offer": "v=0"}"#)), Some("offer"));
+        assert_eq!(description_type(&envelope(r#"{"payload": {"candidate": "candidate:1"}}"#)), None);
+    }
+
+    #[test]
+    fn detects_crossing_offers() {
+        let mut offers = PendingOffers::default();
+        assert!(!offers.offer("alice", "bob", WINDOW));
+        assert!(!offers.offer("alice", "bob", WINDOW));
+        assert!(offers.offer("bob", "alice", WINDOW));
+        assert!(!offers.offer("alice", "carol", WINDOW));
+    }
+
+    #[test]
+    fn forgets_answered_offers() {
+        let mut offers = PendingOffers::default();
+        offers.offer("alice", "bob", WINDOW);
+        offers.forget("alice", "bob");
+        assert!(!offers.offer("bob", "alice", WINDOW));
+    }
+
+    #[test]
+    fn forgets_offers_outside_the_window() {
+        let mut offers = PendingOffers::default();
+        offers.offer("alice", "bob", WINDOW);
+        assert!(!offers.offer("bob", "alice", Duration::from_secs(0)));
+    }
+}
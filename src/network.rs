@@ -9,6 +9,9 @@ use std::rc::Rc;
 use std::rc::Weak;
 use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+use serde_json::Value;
 #[cfg(feature = "push")]
 use std::{
     fs::File,
@@ -25,8 +28,49 @@ use futures::{
 #[cfg(feature = "push")]
 use web_push::*;
 
+use ws::CloseCode;
+
 use node::Node;
-use room::Room;
+use room::{Moderation, Room, RoomRole, MAX_METADATA_SIZE, MAX_STATE_KEY_SIZE, MAX_STATE_VALUE_SIZE, MAX_TOPIC_SIZE};
+use config::{Config, DuplicatePolicy};
+use message::{Delivery, ack_frame, call_state_frame, member_joined_frame, member_left_frame,
+    member_list_frame, glare_frame, moderation_frame, room_updated_frame,
+    room_list_frame, state_changed_frame, waiting_frame, presence_frame, ring_frame, welcome_frame};
+use mailbox::{Mailboxes, QueuedMessage};
+use call::{Call, CallState};
+use negotiation::{PendingOffers, Role};
+use auth::Authenticator;
+use access::{Invite, invite_id, tenant_secret, verify_invite, verify_password};
+use session::{Reservation, Session, generate_token, tokens_match};
+use error::{SignalError, SignalResult, CLOSE_REPLACED};
+
+/// Adds the node to the room, and tells the members about each other.
+fn enter_room(room: &Room, node: &std::rc::Rc<std::cell::RefCell<Node>>) {
+    let members = room.member_names(node);
+    room.add_node(node);
+    node.borrow_mut().rooms.push(room.name.clone());
+
+    // Tell the new member who is here, and everyone else about the new member
+    node.borrow().sender.send(member_list_frame(room, &members)).ok();
+    if let Some(owner) = node.borrow().owner.as_ref() {
+        if !members.contains(owner) {
+            room.broadcast(&member_joined_frame(&room.name, owner), node);
+        }
+    }
+}
+
+/// The role of the node's user in the room. Nodes without a username are members.
+fn role_in(room: &Room, node: &std::rc::Rc<std::cell::RefCell<Node>>) -> RoomRole {
+    node.borrow().owner.as_ref().map_or(RoomRole::Member, |owner| room.role_of(owner))
+}
+
+/// How many rooms are listed at once, unless the client asks for another number.
+const DEFAULT_PAGE_SIZE: usize = 50;
+/// The most rooms that are listed at once.
+const MAX_PAGE_SIZE: usize = 100;
+
+/// The nodes watching the presence of each username.
+pub type Watchers = HashMap<String, Vec<Weak<RefCell<Node>>>>;
 
 /// A network for keeping track of the connected nodes and the pushmap.
 /// The weak pointer to the nodes will allow nodes to disconnect, and 
@@ -46,6 +90,14 @@ pub struct Network {
     pub nodemap: Rc<RefCell<HashMap<String, Weak<RefCell<Node>>>>>,
     pub pushmap: Rc<RefCell<HashMap<String, String>>>,
     pub rooms: Rc<RefCell<HashSet<Room>>>,
+    pub reservations: Rc<RefCell<HashMap<String, Reservation>>>,
+    pub watchers: Rc<RefCell<Watchers>>,
+    pub mailboxes: Rc<RefCell<Mailboxes>>,
+    pub calls: Rc<RefCell<HashMap<String, Call>>>,
+    next_call_token: usize,
+    pub offers: PendingOffers,
+    pub authenticator: Option<Authenticator>,
+    pub config: Config,
 
     pub vapid_path: String,
 }
@@ -61,38 +113,765 @@ pub struct Network {
 pub struct Network {
     pub nodemap: Rc<RefCell<HashMap<String, Weak<RefCell<Node>>>>>,
     pub rooms: Rc<RefCell<HashSet<Room>>>,
+    pub reservations: Rc<RefCell<HashMap<String, Reservation>>>,
+    pub watchers: Rc<RefCell<Watchers>>,
+    pub mailboxes: Rc<RefCell<Mailboxes>>,
+    pub calls: Rc<RefCell<HashMap<String, Call>>>,
+    next_call_token: usize,
+    pub offers: PendingOffers,
+    pub authenticator: Option<Authenticator>,
+    pub config: Config,
 }
 
 impl Network {
-    /// Adds a user to the network, making sure to not override current usernames on the network
+    /// Adds a user to the network, making sure to not override current usernames on the network.
+    /// The node is welcomed with its session, including a resume token,
+    /// followed by any messages that were queued while the user was offline.
+    /// A node with a valid resume token takes over the session of the username, including its rooms.
+    /// Otherwise, if the username is taken, the configured policy decides whether the user is rejected,
+    /// replaces the node holding the username, or is given another username.
     #[inline]
-    pub fn add_user(&mut self, owner: &str, node: &std::rc::Rc<std::cell::RefCell<Node>>) {
-        if !self.nodemap.borrow().contains_key(owner) {
-            node.borrow_mut().owner = Some(owner.into());
-            self.nodemap.borrow_mut().insert(owner.to_string(), Rc::downgrade(node));
-            println!("Node {:?} connected to the network.", owner);
+    pub fn add_user(&mut self, owner: &str, node: &std::rc::Rc<std::cell::RefCell<Node>>, resume_token: Option<&str>) -> Result<Session, SignalError> {
+        self.reservations.borrow_mut().retain(|_, reservation| !reservation.is_expired());
+
+        if let Some(rooms) = resume_token.and_then(|token| self.take_session(owner, token)) {
+            let session = Session { resumed: true, ..self.register(owner, node) };
+            self.welcome(&session, node);
+            // The rooms were kept while the session was reserved, with their owner, password and members.
+            // A room the user can no longer enter, e.g. because it was banned or the room filled up, is reported
+            for room_name in rooms.iter() {
+                if let Err(error) = self.add_user_to_room(room_name, node, None, None, false) {
+                    node.borrow().sender.send(error.to_frame(None)).ok();
+                }
+            }
+            println!("Node {:?} resumed its session.", owner);
+            return Ok(session);
+        }
+
+        let owner = if !self.is_taken(owner) {
+            owner.to_string()
+        } else {
+            match self.config.duplicate_usernames {
+                DuplicatePolicy::RejectNew => {
+                    println!("{:?} tried to connect, but the username was taken", owner);
+                    return Err(SignalError::UsernameTaken(owner.to_string()));
+                },
+                DuplicatePolicy::ReplaceOld => {
+                    println!("{:?} reconnected, closing the previous connection", owner);
+                    self.reservations.borrow_mut().remove(owner);
+                    if let Some(existing_node) = self.get_node(owner) {
+                        existing_node.borrow().sender.close_with_reason(
+                            CloseCode::from(CLOSE_REPLACED),
+                            "Replaced by a new connection"
+                        ).ok();
+                    }
+                    owner.to_string()
+                },
+                DuplicatePolicy::AutoSuffix => {
+                    (2..).map(|suffix| format!("{}-{}", owner, suffix))
+                        .find(|username| !self.is_taken(username))
+                        .unwrap_or_default()
+                },
+            }
+        };
+
+        let session = self.register(&owner, node);
+        self.welcome(&session, node);
+        Ok(session)
+    }
+
+    /// Binds the username to the node, and gives it a new resume token if sessions can be resumed.
+    fn register(&mut self, owner: &str, node: &std::rc::Rc<std::cell::RefCell<Node>>) -> Session {
+        let resume_token = self.config.resume_grace_period.map(|_| generate_token());
+        {
+            let mut node = node.borrow_mut();
+            node.owner = Some(owner.into());
+            node.resume_token = resume_token.clone();
+        }
+        self.nodemap.borrow_mut().insert(owner.to_string(), Rc::downgrade(node));
+        println!("Node {:?} connected to the network.", owner);
+        self.notify_watchers(owner, true, None);
+
+        Session {
+            owner: owner.to_string(),
+            resume_token,
+            resumed: false,
+        }
+    }
+
+    /// Greets the newly registered node, then hands it the messages that were queued for it,
+    /// and rings it for calls that are waiting for it to answer.
+    fn welcome(&mut self, session: &Session, node: &std::rc::Rc<std::cell::RefCell<Node>>) {
+        node.borrow().sender.send(welcome_frame(session)).ok();
+        self.flush_mailbox(&session.owner, node);
+
+        // Ring calls that were made while the user was offline
+        self.expire_calls();
+        for call in self.calls.borrow().values().filter(|call| call.callee == session.owner) {
+            node.borrow().sender.send(ring_frame(call)).ok();
+        }
+    }
+
+    /// Takes over the session of a username, returning the rooms it was in.
+    /// The session is either reserved after a disconnect, or still held by a connection
+    /// that has not noticed it is dead yet, in which case that connection is closed.
+    fn take_session(&mut self, owner: &str, token: &str) -> Option<Vec<String>> {
+        if let Some(existing_node) = self.get_node(owner) {
+            let existing_node = existing_node.borrow();
+            return match existing_node.resume_token.as_ref() {
+                Some(expected) if tokens_match(expected, token) => {
+                    existing_node.sender.close_with_reason(
+                        CloseCode::from(CLOSE_REPLACED),
+                        "The session was resumed by a new connection"
+                    ).ok();
+                    Some(existing_node.rooms.clone())
+                },
+                _ => None,
+            };
+        }
+
+        let mut reservations = self.reservations.borrow_mut();
+        let matches = reservations.get(owner)
+            .is_some_and(|reservation| tokens_match(&reservation.token, token));
+        if matches {
+            reservations.remove(owner).map(|reservation| reservation.rooms)
         } else {
-            println!("{:?} tried to connect, but the username was taken", owner);
-            node.borrow().sender.send("The username is taken").ok();
+            None
         }
     }
 
-    pub fn create_room(&mut self, room_name: &str) {
-        if self.rooms.borrow_mut().insert(Room::new(room_name)) {
+    /// Checks if the session of a username could be resumed with the token.
+    pub fn can_resume(&self, owner: &str, token: &str) -> bool {
+        if let Some(existing_node) = self.get_node(owner) {
+            return existing_node.borrow().resume_token.as_ref()
+                .is_some_and(|expected| tokens_match(expected, token));
+        }
+        self.reservations.borrow().get(owner)
+            .is_some_and(|reservation| !reservation.is_expired() && tokens_match(&reservation.token, token))
+    }
+
+    /// Retrieves the connected node with the username, if any.
+    pub fn get_node(&self, owner: &str) -> Option<Rc<RefCell<Node>>> {
+        self.nodemap.borrow().get(owner).and_then(|node| node.upgrade())
+    }
+
+    /// Checks if a connected node already uses the username,
+    /// or if it is reserved for a disconnected node to resume its session.
+    pub fn is_taken(&self, owner: &str) -> bool {
+        self.get_node(owner).is_some() || self.reservations.borrow().get(owner)
+            .is_some_and(|reservation| !reservation.is_expired())
+    }
+
+    /// Adds the room to the network, unless a room with the name exists.
+    /// Returns true if the room was created.
+    /// Rooms created without a capacity get the configured one.
+    pub fn create_room(&mut self, mut room: Room) -> bool {
+        self.collect_rooms();
+        room.capacity = room.capacity.or(self.config.room_capacity);
+        let room_name = room.name.clone();
+        let created = self.rooms.borrow_mut().insert(room);
+        if created {
             println!("Created new room {:?}", room_name);
+        }
+        created
+    }
+
+    /// Adds the node to the room, if it may enter it.
+    /// A private room is entered with its password or an invite, and
+    /// users that entered it before may enter again without one.
+    /// If the room is full, the node is put on its waiting list if it asked to wait.
+    #[inline]
+    pub fn add_user_to_room(&mut self, room_name: &str, node: &std::rc::Rc<std::cell::RefCell<Node>>,
+        password: Option<&str>, invite: Option<&str>, wait: bool) -> SignalResult {
+        if node.borrow().rooms.iter().any(|joined| joined == room_name) {
+            return Ok(());
+        }
+        let rooms = self.rooms.borrow();
+        let room = rooms.get(room_name).ok_or_else(|| SignalError::RoomNotFound(room_name.to_string()))?;
+
+        if node.borrow().owner.as_ref().is_some_and(|owner| room.banned.borrow().contains(owner)) {
+            return Err(SignalError::Banned(room_name.to_string()));
+        }
+        if room.is_private() {
+            let owner = node.borrow().owner.clone()
+                .ok_or_else(|| SignalError::RoomAccessDenied(room_name.to_string()))?;
+            if !self.may_enter(room, &owner, password, invite) {
+                return Err(SignalError::RoomAccessDenied(room_name.to_string()));
+            }
+            room.admitted.borrow_mut().insert(owner);
+        }
+
+        if room.is_full(node) {
+            if !wait {
+                return Err(SignalError::RoomFull(room_name.to_string()));
+            }
+            let position = room.wait(node);
+            node.borrow().sender.send(waiting_frame(room_name, position)).ok();
+            return Ok(());
+        }
+
+        enter_room(room, node);
+        Ok(())
+    }
+
+    fn may_enter(&self, room: &Room, owner: &String, password: Option<&str>, invite: Option<&str>) -> bool {
+        if room.role_of(owner) > RoomRole::Member || room.admitted.borrow().contains(owner) {
+            return true;
+        }
+
+        let password_matches = match (room.password_hash.as_ref(), password) {
+            (Some(hash), Some(password)) => verify_password(hash, password),
+            _ => false,
         };
+        let invited = invite
+            .and_then(|invite| verify_invite(&self.config.invite_secret, &room.name, &room.nonce, invite))
+            .is_some_and(|id| !room.revoked_invites.borrow().contains(id));
+        password_matches || invited
     }
 
+    /// Mints an invite to a private room. Only the owner and the moderators can invite users.
+    pub fn create_invite(&self, room_name: &str, node: &std::rc::Rc<std::cell::RefCell<Node>>) -> Result<Invite, SignalError> {
+        let rooms = self.rooms.borrow();
+        let room = rooms.get(room_name).ok_or_else(|| SignalError::RoomNotFound(room_name.to_string()))?;
+        if role_in(room, node) < RoomRole::Moderator {
+            return Err(SignalError::NotPermitted(room_name.to_string()));
+        }
+        if !room.is_private() {
+            return Err(SignalError::RoomNotPrivate(room_name.to_string()));
+        }
+        Ok(Invite::new(&self.config.invite_secret, room_name, &room.nonce, self.config.invite_ttl))
+    }
+
+    /// Revokes an invite to a private room, given its id or token,
+    /// so it no longer lets anyone enter. Users that already entered stay.
+    pub fn revoke_invite(&self, room_name: &str, invite: &str, node: &std::rc::Rc<std::cell::RefCell<Node>>) -> SignalResult {
+        let rooms = self.rooms.borrow();
+        let room = rooms.get(room_name).ok_or_else(|| SignalError::RoomNotFound(room_name.to_string()))?;
+        if role_in(room, node) < RoomRole::Moderator {
+            return Err(SignalError::NotPermitted(room_name.to_string()));
+        }
+        room.revoked_invites.borrow_mut().insert(invite_id(invite).to_string());
+        Ok(())
+    }
+
+    /// Carries out a moderation command on users of a room, and tells the members about it.
+    /// Moderators can only kick, ban or mute users with a lower role than their own.
+    pub fn moderate(&mut self, room_name: &str, action: Moderation, users: &[String],
+        node: &std::rc::Rc<std::cell::RefCell<Node>>) -> SignalResult {
+        let moderator = node.borrow().owner.clone().ok_or(SignalError::Anonymous)?;
+        let rooms = self.rooms.borrow();
+        let room = rooms.get(room_name).ok_or_else(|| SignalError::RoomNotFound(room_name.to_string()))?;
+
+        let role = room.role_of(&moderator);
+        if role < action.required_role() {
+            return Err(SignalError::NotPermitted(room_name.to_string()));
+        }
+        let restricts = matches!(action, Moderation::Kick | Moderation::Ban | Moderation::Mute | Moderation::Demote);
+        if restricts && users.iter().any(|user| room.role_of(user) >= role) {
+            return Err(SignalError::NotPermitted(room_name.to_string()));
+        }
+
+        // A room has one owner, so only the first user is made the owner
+        let count = if action == Moderation::TransferOwnership { 1 } else { users.len() };
+        let targets: Vec<(&String, Vec<Rc<RefCell<Node>>>)> = users.iter().take(count)
+            .map(|user| {
+                let members = room.members().into_iter()
+                    .filter(|member| member.borrow().owner.as_ref() == Some(user))
+                    .collect();
+                (user, members)
+            })
+            .collect();
+
+        // Every user is checked before the command is carried out for any of them
+        if action == Moderation::Kick || action == Moderation::TransferOwnership {
+            if let Some((user, _)) = targets.iter().find(|(_, members)| members.is_empty()) {
+                return Err(SignalError::UserNotFound(user.to_string()));
+            }
+        }
+
+        for (user, members) in targets {
+            match action {
+                Moderation::Ban => { room.banned.borrow_mut().insert(user.clone()); },
+                Moderation::Unban => { room.banned.borrow_mut().remove(user); },
+                Moderation::Mute => { room.muted.borrow_mut().insert(user.clone()); },
+                Moderation::Unmute => { room.muted.borrow_mut().remove(user); },
+                Moderation::Promote => { room.moderators.borrow_mut().insert(user.clone()); },
+                Moderation::Demote => { room.moderators.borrow_mut().remove(user); },
+                Moderation::TransferOwnership => {
+                    room.moderators.borrow_mut().remove(user);
+                    room.moderators.borrow_mut().insert(moderator.clone());
+                    room.owner.replace(Some(user.clone()));
+                },
+                Moderation::Kick => {},
+            }
+
+            // Everyone is told, including the user, before it is removed from the room
+            let frame = moderation_frame(room_name, action, user, &moderator);
+            for member in room.members() {
+                member.borrow().sender.send(frame.as_str()).ok();
+            }
+
+            if action == Moderation::Kick || action == Moderation::Ban {
+                room.admitted.borrow_mut().remove(user);
+                room.stop_waiting_user(user);
+                for member in members.iter() {
+                    self.leave_room(room, member);
+                    member.borrow_mut().rooms.retain(|joined| joined != room_name);
+                }
+            }
+            println!("{:?} gave the command {:?} for {:?} in {:?}", moderator, action.as_str(), user, room_name);
+        }
+        Ok(())
+    }
+
+    /// Builds a page of the rooms that are not hidden, sorted by name.
+    /// Rooms are listed after the room named `after`, and filtered on the start of their names.
+    pub fn list_rooms(&mut self, prefix: Option<&str>, after: Option<&str>, limit: Option<usize>,
+        reference: Option<&str>) -> String {
+        self.collect_rooms();
+        let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+        let all_rooms = self.rooms.borrow();
+        let mut rooms: Vec<&Room> = all_rooms.iter()
+            .filter(|room| !room.hidden.get())
+            .filter(|room| prefix.is_none_or(|prefix| room.name.starts_with(prefix)))
+            .filter(|room| after.is_none_or(|after| room.name.as_str() > after))
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let next = if rooms.len() > limit {
+            rooms.truncate(limit);
+            rooms.last().map(|room| room.name.as_str())
+        } else {
+            None
+        };
+        room_list_frame(&rooms, next, reference)
+    }
+
+    /// Changes the topic, metadata or visibility of a room, and tells the members.
+    /// Only the owner and the moderators can update a room.
+    pub fn update_room(&self, room_name: &str, topic: Option<&String>, metadata: Option<&Value>, hidden: Option<bool>,
+        node: &std::rc::Rc<std::cell::RefCell<Node>>) -> SignalResult {
+        let rooms = self.rooms.borrow();
+        let room = rooms.get(room_name).ok_or_else(|| SignalError::RoomNotFound(room_name.to_string()))?;
+        if role_in(room, node) < RoomRole::Moderator {
+            return Err(SignalError::NotPermitted(room_name.to_string()));
+        }
+        if topic.is_some_and(|topic| topic.len() > MAX_TOPIC_SIZE) {
+            return Err(SignalError::TooLarge("topic"));
+        }
+        if metadata.is_some_and(|metadata| metadata.to_string().len() > MAX_METADATA_SIZE) {
+            return Err(SignalError::TooLarge("metadata"));
+        }
+
+        if let Some(topic) = topic {
+            room.topic.replace(Some(topic.clone()));
+        }
+        if let Some(metadata) = metadata {
+            room.metadata.replace(metadata.clone());
+        }
+        if let Some(hidden) = hidden {
+            room.hidden.set(hidden);
+        }
+
+        let frame = room_updated_frame(room, node.borrow().owner.as_deref().unwrap_or_default());
+        for member in room.members() {
+            member.borrow().sender.send(frame.as_str()).ok();
+        }
+        Ok(())
+    }
+
+    /// Sets a key of the shared state of a room, or deletes it if there is no value,
+    /// and tells the members. Only members can change the state.
+    pub fn change_room_state(&self, room_name: &str, key: &str, value: Option<&Value>,
+        node: &std::rc::Rc<std::cell::RefCell<Node>>) -> SignalResult {
+        let rooms = self.rooms.borrow();
+        let room = rooms.get(room_name).ok_or_else(|| SignalError::RoomNotFound(room_name.to_string()))?;
+        if !room.has_member(node) {
+            return Err(SignalError::NotInRoom(room_name.to_string()));
+        }
+        if key.len() > MAX_STATE_KEY_SIZE {
+            return Err(SignalError::TooLarge("key"));
+        }
+
+        match value {
+            Some(value) => {
+                if value.to_string().len() > MAX_STATE_VALUE_SIZE {
+                    return Err(SignalError::TooLarge("value"));
+                }
+                let mut state = room.state.borrow_mut();
+                if !state.contains_key(key) && state.len() >= self.config.room_state_size {
+                    return Err(SignalError::RoomStateFull(room_name.to_string()));
+                }
+                state.insert(key.to_string(), value.clone());
+            },
+            None => {
+                room.state.borrow_mut().remove(key);
+            },
+        }
+
+        let frame = state_changed_frame(room_name, key, value, node.borrow().owner.as_deref().unwrap_or_default());
+        for member in room.members() {
+            member.borrow().sender.send(frame.as_str()).ok();
+        }
+        Ok(())
+    }
+
+    /// Checks that the node may send messages to the room.
+    /// Only members may, so users that were kicked or banned can no longer reach the room.
+    pub fn may_speak(&self, room: &Room, node: &std::rc::Rc<std::cell::RefCell<Node>>) -> SignalResult {
+        let owner = node.borrow().owner.clone();
+        if owner.as_ref().is_some_and(|owner| room.banned.borrow().contains(owner)) {
+            return Err(SignalError::Banned(room.name.clone()));
+        }
+        if !room.has_member(node) {
+            return Err(SignalError::NotInRoom(room.name.clone()));
+        }
+        if owner.as_ref().is_some_and(|owner| room.muted.borrow().contains(owner)) {
+            return Err(SignalError::Muted(room.name.clone()));
+        }
+        Ok(())
+    }
+
+    /// Removes the node from the room, and tells the remaining members it left.
+    /// Nodes waiting for a place in the room are let in as places free up.
+    fn leave_room(&self, room: &Room, node: &std::rc::Rc<std::cell::RefCell<Node>>) {
+        room.remove_node(node);
+        if let Some(owner) = node.borrow().owner.as_ref() {
+            if !room.member_names(node).contains(owner) {
+                room.broadcast(&member_left_frame(&room.name, owner), node);
+            }
+        }
+
+        while let Some(waiting) = room.next_waiting() {
+            enter_room(room, &waiting);
+        }
+    }
+
+    /// Removes a user from a room it is a member of.
+    /// A node on the waiting list of the room stops waiting instead.
     #[inline]
-    pub fn add_user_to_room(&mut self, room_name: &str, node: &std::rc::Rc<std::cell::RefCell<Node>>) {
-        self.rooms.borrow_mut().get(room_name).and_then(|room| 
-            Some(room.nodes.borrow_mut().push(Rc::downgrade(node))));       
+    pub fn remove_user_from_room(&mut self, room_name: &str, node: &std::rc::Rc<std::cell::RefCell<Node>>) -> SignalResult {
+        if self.rooms.borrow().get(room_name).is_some_and(|room| room.stop_waiting(node)) {
+            return Ok(());
+        }
+        if !node.borrow().rooms.iter().any(|joined| joined == room_name) {
+            return Err(SignalError::NotInRoom(room_name.to_string()));
+        }
+        if let Some(room) = self.rooms.borrow().get(room_name) {
+            self.leave_room(room, node);
+        }
+        node.borrow_mut().rooms.retain(|joined| joined != room_name);
+        self.collect_rooms();
+        Ok(())
+    }
+
+    /// Removes rooms that have been empty for longer than the configured linger time.
+    /// Members that have disconnected without leaving are pruned first.
+    /// Rooms are kept while a member's session is reserved, so it can resume into the same room.
+    pub fn collect_rooms(&mut self) {
+        let linger = self.config.room_linger;
+        let reservations = self.reservations.borrow();
+        let reserved: HashSet<&str> = reservations.values()
+            .filter(|reservation| !reservation.is_expired())
+            .flat_map(|reservation| reservation.rooms.iter().map(String::as_str))
+            .collect();
+
+        self.rooms.borrow_mut().retain(|room| {
+            room.prune();
+            let lingered = room.has_lingered(linger) && !reserved.contains(room.name.as_str());
+            if lingered {
+                println!("Removed empty room {:?}", room.name);
+            }
+            !lingered
+        });
     }
 
     /// Removes a user from the network, typically when the connection is ended.
+    /// The username is only released if it still belongs to the node,
+    /// since a replaced node closes after the new node took over its username.
+    /// The node always leaves its rooms, and rooms left empty are collected.
+    /// If sessions can be resumed, the username and rooms are reserved for the grace period.
     #[inline]    
-    pub fn remove(&mut self, owner: &str) {
+    pub fn remove(&mut self, owner: &str, node: &std::rc::Rc<std::cell::RefCell<Node>>) {
+        for room_name in node.borrow().rooms.iter() {
+            if let Some(room) = self.rooms.borrow().get(room_name.as_str()) {
+                self.leave_room(room, node);
+            }
+        }
+
+        let watching = node.borrow().watching.clone();
+        self.unwatch(&watching, node);
+
+        let belongs_to_node = self.nodemap.borrow().get(owner)
+            .is_some_and(|registered| registered.ptr_eq(&Rc::downgrade(node)));
+        if !belongs_to_node {
+            self.collect_rooms();
+            return;
+        }
         self.nodemap.borrow_mut().remove(owner);
+        self.notify_watchers(owner, false, None);
+
+        // A caller that is gone can not be told about the answer, so its calls are cancelled
+        let cancelled: Vec<String> = self.calls.borrow().values()
+            .filter(|call| call.caller == owner)
+            .map(|call| call.id.clone())
+            .collect();
+        for call_id in cancelled.iter() {
+            let call = self.calls.borrow_mut().remove(call_id);
+            if let Some(call) = call {
+                self.end_call(&call, CallState::Cancelled);
+            }
+        }
+
+        let resume_token = node.borrow().resume_token.clone();
+        if let (Some(grace_period), Some(token)) = (self.config.resume_grace_period, resume_token) {
+            let reservation = Reservation::new(token, node.borrow().rooms.clone(), grace_period);
+            self.reservations.borrow_mut().insert(owner.to_string(), reservation);
+            println!("Reserved the session of {:?} for {:?}", owner, grace_period);
+        }
+
+        // Rooms are collected after the session was reserved, so the rooms it was in are kept
+        self.collect_rooms();
+    }
+
+    /// Queues a message for a user that is offline, to be delivered when it connects.
+    /// Expired messages of every user are swept first, so mailboxes of users that never
+    /// connect do not pile up, and the number of messages queued over all users is limited.
+    pub fn enqueue(&mut self, endpoint: &str, message: QueuedMessage) -> SignalResult {
+        let mut mailboxes = self.mailboxes.borrow_mut();
+        mailboxes.retain(|_, mailbox| {
+            mailbox.retain(|queued| !queued.is_expired());
+            !mailbox.is_empty()
+        });
+
+        let total: usize = mailboxes.values().map(|mailbox| mailbox.len()).sum();
+        if total >= self.config.queue_total {
+            return Err(SignalError::QueueFull(endpoint.to_string()));
+        }
+        let mailbox = mailboxes.entry(endpoint.to_string()).or_default();
+        if mailbox.len() >= self.config.queue_size {
+            return Err(SignalError::QueueFull(endpoint.to_string()));
+        }
+        mailbox.push_back(message);
+        Ok(())
+    }
+
+    /// Delivers the messages queued for the user to its node,
+    /// and sends the senders a receipt for each delivered message.
+    fn flush_mailbox(&self, owner: &str, node: &std::rc::Rc<std::cell::RefCell<Node>>) {
+        let mailbox = match self.mailboxes.borrow_mut().remove(owner) {
+            Some(mailbox) => mailbox,
+            None => return,
+        };
+
+        for queued in mailbox.into_iter().filter(|queued| !queued.is_expired()) {
+            if node.borrow().sender.send(queued.text).is_err() {
+                continue;
+            }
+            let sender_node = queued.from.as_ref().and_then(|from| self.get_node(from));
+            if let (Some(sender_node), Some(id)) = (sender_node, queued.id.as_ref()) {
+                sender_node.borrow().sender.send(ack_frame(id, Delivery::Delivered)).ok();
+            }
+        }
+    }
+
+    /// Calls the callee, ringing it in-band if it is online, or with a push notification if it is not.
+    /// The caller is told the call is ringing, and the returned token identifies the call
+    /// when its ring timeout passes.
+    pub fn start_call(&mut self, callee: &str, payload: Value, node: &std::rc::Rc<std::cell::RefCell<Node>>) -> Result<usize, SignalError> {
+        let caller = node.borrow().owner.clone().ok_or(SignalError::Anonymous)?;
+        if caller == callee {
+            return Err(SignalError::CallingSelf);
+        }
+        self.expire_calls();
+        self.next_call_token += 1;
+        let call = Call {
+            id: generate_token(),
+            caller,
+            callee: callee.to_string(),
+            payload,
+            timeout_token: self.next_call_token,
+            started_at: Instant::now(),
+        };
+
+        match self.get_node(callee) {
+            Some(callee_node) => {
+                let ring = callee_node.borrow().sender.send(ring_frame(&call));
+                ring.map_err(|_| SignalError::PeerOffline(callee.to_string()))?;
+            },
+            #[cfg(feature = "push")]
+            None => {
+                self.send_push(&call.caller, callee, &call.id)
+                    .map_err(|_| SignalError::PeerOffline(callee.to_string()))?;
+            },
+            #[cfg(not(feature = "push"))]
+            None => return Err(SignalError::PeerOffline(callee.to_string())),
+        }
+
+        println!("{:?} is calling {:?}", call.caller, call.callee);
+        node.borrow().sender.send(call_state_frame(&call, CallState::Ringing)).ok();
+        let timeout_token = call.timeout_token;
+        self.calls.borrow_mut().insert(call.id.clone(), call);
+        Ok(timeout_token)
+    }
+
+    /// Answers a ringing call. Only the callee may accept or reject it,
+    /// and only the caller may cancel it.
+    pub fn answer_call(&mut self, call_id: &str, state: CallState, node: &std::rc::Rc<std::cell::RefCell<Node>>) -> SignalResult {
+        let owner = node.borrow().owner.clone().ok_or(SignalError::Anonymous)?;
+        self.expire_calls();
+
+        let allowed = self.calls.borrow().get(call_id).is_some_and(|call| match state {
+            CallState::Accepted | CallState::Rejected => call.callee == owner,
+            CallState::Cancelled => call.caller == owner,
+            _ => false,
+        });
+        if !allowed {
+            return Err(SignalError::CallNotFound(call_id.to_string()));
+        }
+
+        let call = self.calls.borrow_mut().remove(call_id);
+        if let Some(call) = call {
+            self.end_call(&call, state);
+        }
+        Ok(())
+    }
+
+    /// Ends the call whose ring timeout passed, if it is still ringing.
+    pub fn time_out_call(&mut self, timeout_token: usize) {
+        let call_id = self.calls.borrow().values()
+            .find(|call| call.timeout_token == timeout_token)
+            .map(|call| call.id.clone());
+        let call = call_id.and_then(|call_id| self.calls.borrow_mut().remove(&call_id));
+        if let Some(call) = call {
+            self.end_call(&call, CallState::Timeout);
+        }
+    }
+
+    /// Ends every call that has been ringing for longer than the ring timeout.
+    fn expire_calls(&mut self) {
+        let ring_timeout = self.config.ring_timeout;
+        let expired: Vec<String> = self.calls.borrow().values()
+            .filter(|call| call.is_expired(ring_timeout))
+            .map(|call| call.id.clone())
+            .collect();
+        for call_id in expired.iter() {
+            let call = self.calls.borrow_mut().remove(call_id);
+            if let Some(call) = call {
+                self.end_call(&call, CallState::Timeout);
+            }
+        }
+    }
+
+    /// Tells the caller and callee how the call ended.
+    fn end_call(&self, call: &Call, state: CallState) {
+        println!("The call from {:?} to {:?} ended: {}", call.caller, call.callee, state.as_str());
+        let frame = call_state_frame(call, state);
+        for user in [&call.caller, &call.callee].iter() {
+            if let Some(user_node) = self.get_node(user) {
+                user_node.borrow().sender.send(frame.as_str()).ok();
+            }
+        }
+    }
+
+    /// Subscribes the node to presence changes of the users,
+    /// and tells it whether they are online right now.
+    pub fn watch(&mut self, users: &[String], node: &std::rc::Rc<std::cell::RefCell<Node>>) {
+        for user in users.iter() {
+            if !node.borrow().watching.contains(user) {
+                node.borrow_mut().watching.push(user.clone());
+                self.watchers.borrow_mut().entry(user.clone())
+                    .or_default()
+                    .push(Rc::downgrade(node));
+            }
+
+            let frame = match self.get_node(user) {
+                Some(watched_node) => presence_frame(user, true, watched_node.borrow().status.as_ref()),
+                None => presence_frame(user, false, None),
+            };
+            node.borrow().sender.send(frame).ok();
+        }
+    }
+
+    /// Unsubscribes the node from presence changes of the users.
+    pub fn unwatch(&mut self, users: &[String], node: &std::rc::Rc<std::cell::RefCell<Node>>) {
+        let weak_node = Rc::downgrade(node);
+        let mut watchers = self.watchers.borrow_mut();
+        for user in users.iter() {
+            if let Some(user_watchers) = watchers.get_mut(user) {
+                user_watchers.retain(|watcher| watcher.upgrade().is_some() && !watcher.ptr_eq(&weak_node));
+                if user_watchers.is_empty() {
+                    watchers.remove(user);
+                }
+            }
+        }
+        node.borrow_mut().watching.retain(|user| !users.contains(user));
+    }
+
+    /// Keeps track of the unanswered offers between users, and resolves the glare
+    /// when an offer crosses one sent the other way, by telling both users their role.
+    /// Returns false if the offer lost the glare, so it should not be relayed.
+    pub fn negotiate(&mut self, from: &str, to: &str, description_type: &str) -> bool {
+        let window = self.config.negotiation_window;
+        match description_type {
+            "offer" if window.as_secs() > 0 => {
+                if !self.offers.offer(from, to, window) {
+                    return true;
+                }
+
+                let role = Role::of(from, to);
+                let peer_role = Role::of(to, from);
+                match role {
+                    Role::Polite => self.offers.forget(from, to),
+                    Role::Impolite => self.offers.forget(to, from),
+                }
+                println!("Offers between {:?} and {:?} crossed, {:?} rolls back", from, to,
+                    if role == Role::Polite { from } else { to });
+
+                if let Some(node) = self.get_node(from) {
+                    node.borrow().sender.send(glare_frame(to, role)).ok();
+                }
+                if let Some(node) = self.get_node(to) {
+                    node.borrow().sender.send(glare_frame(from, peer_role)).ok();
+                }
+                role == Role::Impolite
+            },
+            "answer" => {
+                self.offers.forget(to, from);
+                true
+            },
+            "rollback" => {
+                self.offers.forget(from, to);
+                true
+            },
+            _ => true,
+        }
+    }
+
+    /// Sets a custom status of the node, such as away or busy, and tells its watchers.
+    pub fn set_status(&mut self, status: Option<String>, node: &std::rc::Rc<std::cell::RefCell<Node>>) -> SignalResult {
+        let owner = node.borrow().owner.clone().ok_or(SignalError::Anonymous)?;
+        node.borrow_mut().status = status.clone();
+        self.notify_watchers(&owner, true, status.as_ref());
+        Ok(())
+    }
+
+    /// Tells every node watching the user about its presence.
+    fn notify_watchers(&self, owner: &str, online: bool, status: Option<&String>) {
+        if let Some(user_watchers) = self.watchers.borrow().get(owner) {
+            let frame = presence_frame(owner, online, status);
+            for watcher in user_watchers.iter().filter_map(|watcher| watcher.upgrade()) {
+                watcher.borrow().sender.send(frame.as_str()).ok();
+            }
+        }
+    }
+
+    /// Sends a message to every node connected to the network.
+    /// Nodes of other tenants are on other networks, so they never receive it.
+    pub fn broadcast(&self, message: &str) {
+        for node in self.nodemap.borrow().values().filter_map(Weak::upgrade) {
+            node.borrow().sender.send(message).ok();
+        }
     }
 
     /// Retrieves the number of connected nodes on the network, useful for balance loading.
@@ -100,16 +879,59 @@ impl Network {
     pub fn size(&self) -> usize {
         self.nodemap.borrow().len()
     }
+
+    /// The number of rooms on the network, not counting empty rooms that have lingered.
+    pub fn room_count(&mut self) -> usize {
+        self.collect_rooms();
+        self.rooms.borrow().len()
+    }
+
+    /// Checks if nothing on the network is in use, so it can be thrown away.
+    pub fn is_idle(&self) -> bool {
+        self.nodemap.borrow().is_empty()
+            && self.rooms.borrow().is_empty()
+            && self.reservations.borrow().is_empty()
+            && self.mailboxes.borrow().is_empty()
+            && self.calls.borrow().is_empty()
+    }
+
+    /// Creates an empty network for another tenant, configured like this one.
+    /// Invites are signed with a secret of the tenant's own.
+    #[cfg(feature = "push")]
+    pub fn configured_like(&self, tenant: &str) -> Network {
+        let mut config = self.config.clone();
+        config.invite_secret = tenant_secret(&self.config.invite_secret, tenant);
+        Network {
+            config,
+            authenticator: self.authenticator.clone(),
+            vapid_path: self.vapid_path.clone(),
+            ..Network::default()
+        }
+    }
+
+    /// Creates an empty network for another tenant, configured like this one.
+    /// Invites are signed with a secret of the tenant's own.
+    #[cfg(not(feature = "push"))]
+    pub fn configured_like(&self, tenant: &str) -> Network {
+        let mut config = self.config.clone();
+        config.invite_secret = tenant_secret(&self.config.invite_secret, tenant);
+        Network {
+            config,
+            authenticator: self.authenticator.clone(),
+            ..Network::default()
+        }
+    }
     
     /// Adds a subscription, that enables the node's browser endpoint to be discovered.
     /// This makes it possible to send push notifications to those subscriptions.
     #[cfg(feature = "push")]
-    pub fn add_subscription(&mut self, subscription: &str, node: &std::rc::Rc<std::cell::RefCell<Node>>) {
-        println!("Node {:?} updated its subscription data", node.borrow().owner);
+    pub fn add_subscription(&mut self, subscription: &str, node: &std::rc::Rc<std::cell::RefCell<Node>>) -> SignalResult {
+        let owner = node.borrow().owner.clone().ok_or(SignalError::Anonymous)?;
+        println!("Node {:?} updated its subscription data", owner);
         node.borrow_mut().subscription = Some(subscription.into());
-        let owner = node.borrow().owner.clone();
         
-        self.pushmap.borrow_mut().insert(owner.unwrap(), subscription.to_string());
+        self.pushmap.borrow_mut().insert(owner, subscription.to_string());
+        Ok(())
     }
 
     /// Sets the system path to a vapid private key used for push
@@ -121,47 +943,824 @@ impl Network {
     /// Sends a push to an endpoint. The endpoint subscription is discovered by 
     /// looking it up in the network's push map.
     #[cfg(feature = "push")]
-    pub fn send_push(&self, sender: &str, endpoint: &str) {
+    pub fn send_push(&self, sender: &str, endpoint: &str, call_id: &str) -> SignalResult {
         println!("!!!!!! Sending PUSH !!!!!!!");
 
         let payload = 
             json!({"body": format!("{}\nwants to connect with you", sender), 
             "sender": sender, 
+            "call": call_id, 
             "actions": [
                 {"action": "allowConnection", "title": "✔️ Allow"}, 
                 {"action": "denyConnection", "title": "✖️ Deny"}]}).to_string();
 
-        if let Some(subscription) = self.pushmap.borrow().get(endpoint) {
-            let subscription_info: SubscriptionInfo = serde_json::from_str(subscription).unwrap();
+        let pushmap = self.pushmap.borrow();
+        let subscription = pushmap.get(endpoint)
+            .ok_or_else(|| SignalError::NoPushSubscription(endpoint.to_string()))?;
+        let push_failed = |error: &dyn std::fmt::Debug| SignalError::PushFailed(format!("{:?}", error));
 
-            let mut builder = WebPushMessageBuilder::new(&subscription_info).unwrap();
-            builder.set_payload(ContentEncoding::AesGcm, payload.as_bytes());
+        let subscription_info: SubscriptionInfo = serde_json::from_str(subscription)
+            .map_err(|error| push_failed(&error))?;
 
-            let vapid_file = File::open(&self.vapid_path).unwrap();
+        let mut builder = WebPushMessageBuilder::new(&subscription_info)
+            .map_err(|error| push_failed(&error))?;
+        builder.set_payload(ContentEncoding::AesGcm, payload.as_bytes());
 
-            let sig_builder = VapidSignatureBuilder::from_pem(vapid_file, &subscription_info).unwrap();
-            let signature = sig_builder.build().unwrap();
+        let vapid_file = File::open(&self.vapid_path).map_err(|error| push_failed(&error))?;
 
-            builder.set_ttl(3600);
-            builder.set_vapid_signature(signature);
+        let sig_builder = VapidSignatureBuilder::from_pem(vapid_file, &subscription_info)
+            .map_err(|error| push_failed(&error))?;
+        let signature = sig_builder.build().map_err(|error| push_failed(&error))?;
 
-            match builder.build() {
-                Ok(message) => {
-                    let client = WebPushClient::new().unwrap();
-                    tokio::run(lazy(move || {
-                        client
-                            .send_with_timeout(message, Duration::from_secs(4))
-                            .map(|response| {
-                                println!("Sent: {:?}", response);
-                            }).map_err(|error| {
-                                println!("Error: {:?}", error)
-                            })
-                    }));
-                },
-                Err(error) => {
-                    println!("ERROR in building message: {:?}", error)
-                }
-            }
+        builder.set_ttl(3600);
+        builder.set_vapid_signature(signature);
+
+        let message = builder.build().map_err(|error| push_failed(&error))?;
+        let client = WebPushClient::new().map_err(|error| push_failed(&error))?;
+        tokio::run(lazy(move || {
+            client
+                .send_with_timeout(message, Duration::from_secs(4))
+                .map(|response| {
+                    println!("Sent: {:?}", response);
+                }).map_err(|error| {
+                    println!("Error: {:?}", error)
+                })
+        }));
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use node::{TestConnection, test_node};
+
+    /// Connects a node with the username, and takes the welcome frame.
+    fn connect(network: &mut Network, owner: &str) -> (Rc<RefCell<Node>>, TestConnection) {
+        let (node, frames) = test_node();
+        network.add_user(owner, &node, None).unwrap();
+        frames.take();
+        (node, frames)
+    }
+
+    /// Creates a room owned by the node's user, and joins it.
+    fn create(network: &mut Network, room: Room, node: &Rc<RefCell<Node>>) {
+        let name = room.name.clone();
+        room.owner.replace(node.borrow().owner.clone());
+        assert!(network.create_room(room));
+        network.add_user_to_room(&name, node, None, None, false).unwrap();
+    }
+
+    fn private_room(name: &str) -> Room {
+        let mut room = Room::new(name);
+        room.password_hash = Some(hash_password("hunter2"));
+        room
+    }
+
+    fn types(frames: &[Value]) -> Vec<&str> {
+        frames.iter().filter_map(|frame| frame["type"].as_str()).collect()
+    }
+
+    use access::hash_password;
+
+    #[test]
+    fn private_rooms_need_a_password_or_invite() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        let (eve, _) = connect(&mut network, "eve");
+        let (bob, _) = connect(&mut network, "bob");
+        create(&mut network, private_room("vault"), &alice);
+
+        assert_eq!(network.add_user_to_room("vault", &eve, None, None, false),
+            Err(SignalError::RoomAccessDenied("vault".to_string())));
+        assert_eq!(network.add_user_to_room("vault", &eve, Some("hunter3"), None, false),
+            Err(SignalError::RoomAccessDenied("vault".to_string())));
+        assert_eq!(network.add_user_to_room("vault", &eve, Some("hunter2"), None, false), Ok(()));
+
+        let invite = network.create_invite("vault", &alice).unwrap();
+        assert_eq!(network.add_user_to_room("vault", &bob, None, Some(&invite.token), false), Ok(()));
+    }
+
+    #[test]
+    fn admitted_users_may_enter_again() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        let (eve, _) = connect(&mut network, "eve");
+        create(&mut network, private_room("vault"), &alice);
+
+        network.add_user_to_room("vault", &eve, Some("hunter2"), None, false).unwrap();
+        network.remove_user_from_room("vault", &eve).unwrap();
+        assert_eq!(network.add_user_to_room("vault", &eve, None, None, false), Ok(()));
+    }
+
+    #[test]
+    fn revoked_invites_let_nobody_in() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        let (eve, _) = connect(&mut network, "eve");
+        let mut room = Room::new("vault");
+        room.invite_only = true;
+        create(&mut network, room, &alice);
+
+        let invite = network.create_invite("vault", &alice).unwrap();
+        network.revoke_invite("vault", &invite.id, &alice).unwrap();
+        assert_eq!(network.add_user_to_room("vault", &eve, None, Some(&invite.token), false),
+            Err(SignalError::RoomAccessDenied("vault".to_string())));
+    }
+
+    #[test]
+    fn only_moderators_invite_to_private_rooms() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        let (eve, _) = connect(&mut network, "eve");
+        create(&mut network, private_room("vault"), &alice);
+        create(&mut network, Room::new("lobby"), &alice);
+        network.add_user_to_room("vault", &eve, Some("hunter2"), None, false).unwrap();
+
+        assert_eq!(network.create_invite("vault", &eve).err(), Some(SignalError::NotPermitted("vault".to_string())));
+        assert_eq!(network.create_invite("lobby", &alice).err(), Some(SignalError::RoomNotPrivate("lobby".to_string())));
+        assert_eq!(network.create_invite("nowhere", &alice).err(), Some(SignalError::RoomNotFound("nowhere".to_string())));
+    }
+
+    #[test]
+    fn resumed_sessions_find_their_private_rooms_as_they_left_them() {
+        let mut network = Network::default();
+        network.config.resume_grace_period = Some(Duration::from_secs(30));
+        let (alice, _) = connect(&mut network, "alice");
+        create(&mut network, private_room("vault"), &alice);
+
+        let token = alice.borrow().resume_token.clone().unwrap();
+        network.remove("alice", &alice);
+        drop(alice);
+        network.collect_rooms();
+        assert!(network.rooms.borrow().contains("vault"));
+
+        let (eve, _) = connect(&mut network, "eve");
+        assert_eq!(network.add_user_to_room("vault", &eve, None, None, false),
+            Err(SignalError::RoomAccessDenied("vault".to_string())));
+
+        let (alice, frames) = test_node();
+        assert!(network.add_user("alice", &alice, Some(&token)).unwrap().resumed);
+        assert_eq!(types(&frames.take()), vec!["welcome", "member-list"]);
+        assert_eq!(network.rooms.borrow().get("vault").map(|room| room.role_of("alice")), Some(RoomRole::Owner));
+    }
+
+    #[test]
+    fn resumed_sessions_report_rooms_they_can_not_enter() {
+        let mut network = Network::default();
+        network.config.resume_grace_period = Some(Duration::from_secs(30));
+        let (alice, _) = connect(&mut network, "alice");
+        let (bob, _) = connect(&mut network, "bob");
+        create(&mut network, Room::new("lobby"), &alice);
+        network.add_user_to_room("lobby", &bob, None, None, false).unwrap();
+
+        let token = bob.borrow().resume_token.clone().unwrap();
+        network.remove("bob", &bob);
+        network.moderate("lobby", Moderation::Ban, &["bob".to_string()], &alice).unwrap();
+
+        let (bob, frames) = test_node();
+        network.add_user("bob", &bob, Some(&token)).unwrap();
+        let frames = frames.take();
+        assert_eq!(types(&frames), vec!["welcome", "error"]);
+        assert_eq!(frames[1]["code"], "banned");
+    }
+
+    #[test]
+    fn empty_rooms_without_reserved_members_are_collected() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        create(&mut network, private_room("vault"), &alice);
+        network.remove("alice", &alice);
+        assert!(!network.rooms.borrow().contains("vault"));
+    }
+
+    #[test]
+    fn only_members_that_are_neither_banned_nor_muted_may_speak() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        let (bob, _) = connect(&mut network, "bob");
+        let (eve, _) = connect(&mut network, "eve");
+        create(&mut network, Room::new("lobby"), &alice);
+        network.add_user_to_room("lobby", &bob, None, None, false).unwrap();
+
+        let rooms = network.rooms.clone();
+        let speaks = |node| network.may_speak(rooms.borrow().get("lobby").unwrap(), node);
+        assert_eq!(speaks(&alice), Ok(()));
+        assert_eq!(speaks(&eve), Err(SignalError::NotInRoom("lobby".to_string())));
+
+        rooms.borrow().get("lobby").unwrap().muted.borrow_mut().insert("bob".to_string());
+        assert_eq!(speaks(&bob), Err(SignalError::Muted("lobby".to_string())));
+        rooms.borrow().get("lobby").unwrap().banned.borrow_mut().insert("eve".to_string());
+        assert_eq!(speaks(&eve), Err(SignalError::Banned("lobby".to_string())));
+    }
+
+    #[test]
+    fn banned_users_are_removed_and_kept_out() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        let (bob, frames) = connect(&mut network, "bob");
+        create(&mut network, Room::new("lobby"), &alice);
+        network.add_user_to_room("lobby", &bob, None, None, false).unwrap();
+        frames.take();
+
+        network.moderate("lobby", Moderation::Ban, &["bob".to_string()], &alice).unwrap();
+        assert_eq!(types(&frames.take()), vec!["moderation"]);
+        assert!(bob.borrow().rooms.is_empty());
+        assert_eq!(network.add_user_to_room("lobby", &bob, None, None, false),
+            Err(SignalError::Banned("lobby".to_string())));
+
+        network.moderate("lobby", Moderation::Unban, &["bob".to_string()], &alice).unwrap();
+        assert_eq!(network.add_user_to_room("lobby", &bob, None, None, false), Ok(()));
+    }
+
+    #[test]
+    fn moderation_is_not_applied_when_a_target_is_missing() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        let (bob, _) = connect(&mut network, "bob");
+        create(&mut network, Room::new("lobby"), &alice);
+        network.add_user_to_room("lobby", &bob, None, None, false).unwrap();
+
+        let users = ["bob".to_string(), "ghost".to_string()];
+        assert_eq!(network.moderate("lobby", Moderation::Kick, &users, &alice),
+            Err(SignalError::UserNotFound("ghost".to_string())));
+        assert_eq!(bob.borrow().rooms, vec!["lobby".to_string()]);
+    }
+
+    #[test]
+    fn moderators_only_act_on_lower_roles() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        let (bob, _) = connect(&mut network, "bob");
+        let (eve, _) = connect(&mut network, "eve");
+        create(&mut network, Room::new("lobby"), &alice);
+        network.add_user_to_room("lobby", &bob, None, None, false).unwrap();
+        network.add_user_to_room("lobby", &eve, None, None, false).unwrap();
+
+        assert_eq!(network.moderate("lobby", Moderation::Mute, &["bob".to_string()], &eve),
+            Err(SignalError::NotPermitted("lobby".to_string())));
+        network.moderate("lobby", Moderation::Promote, &["bob".to_string()], &alice).unwrap();
+        assert_eq!(network.moderate("lobby", Moderation::Kick, &["alice".to_string()], &bob),
+            Err(SignalError::NotPermitted("lobby".to_string())));
+        assert_eq!(network.moderate("lobby", Moderation::Promote, &["eve".to_string()], &bob),
+            Err(SignalError::NotPermitted("lobby".to_string())));
+        assert_eq!(network.moderate("lobby", Moderation::Kick, &["eve".to_string()], &bob), Ok(()));
+    }
+
+    #[test]
+    fn ownership_moves_to_the_first_user() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        let (bob, _) = connect(&mut network, "bob");
+        create(&mut network, Room::new("lobby"), &alice);
+        network.add_user_to_room("lobby", &bob, None, None, false).unwrap();
+
+        network.moderate("lobby", Moderation::TransferOwnership, &["bob".to_string()], &alice).unwrap();
+        let rooms = network.rooms.borrow();
+        let room = rooms.get("lobby").unwrap();
+        assert_eq!(room.role_of("bob"), RoomRole::Owner);
+        assert_eq!(room.role_of("alice"), RoomRole::Moderator);
+    }
+
+    #[test]
+    fn full_rooms_turn_users_away_or_let_them_wait() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        let (bob, bob_frames) = connect(&mut network, "bob");
+        let (eve, _) = connect(&mut network, "eve");
+        let mut room = Room::new("lobby");
+        room.capacity = Some(1);
+        create(&mut network, room, &alice);
+
+        assert_eq!(network.add_user_to_room("lobby", &eve, None, None, false),
+            Err(SignalError::RoomFull("lobby".to_string())));
+        assert_eq!(network.add_user_to_room("lobby", &bob, None, None, true), Ok(()));
+        let frames = bob_frames.take();
+        assert_eq!(types(&frames), vec!["waiting"]);
+        assert_eq!(frames[0]["position"], 1);
+        assert!(bob.borrow().rooms.is_empty());
+
+        network.remove_user_from_room("lobby", &alice).unwrap();
+        assert_eq!(bob.borrow().rooms, vec!["lobby".to_string()]);
+    }
+
+    #[test]
+    fn users_stop_waiting_when_they_leave() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        let (bob, _) = connect(&mut network, "bob");
+        let (eve, _) = connect(&mut network, "eve");
+        let mut room = Room::new("lobby");
+        room.capacity = Some(1);
+        create(&mut network, room, &alice);
+
+        network.add_user_to_room("lobby", &bob, None, None, true).unwrap();
+        network.add_user_to_room("lobby", &eve, None, None, true).unwrap();
+        network.remove_user_from_room("lobby", &bob).unwrap();
+        network.remove_user_from_room("lobby", &alice).unwrap();
+        assert!(bob.borrow().rooms.is_empty());
+        assert_eq!(eve.borrow().rooms, vec!["lobby".to_string()]);
+    }
+
+    #[test]
+    fn rooms_get_the_configured_capacity() {
+        let mut network = Network::default();
+        network.config.room_capacity = Some(1);
+        let (alice, _) = connect(&mut network, "alice");
+        let (bob, _) = connect(&mut network, "bob");
+        create(&mut network, Room::new("lobby"), &alice);
+
+        assert_eq!(network.add_user_to_room("lobby", &bob, None, None, false),
+            Err(SignalError::RoomFull("lobby".to_string())));
+    }
+
+    #[test]
+    fn members_change_the_room_state() {
+        let mut network = Network::default();
+        let (alice, frames) = connect(&mut network, "alice");
+        let (eve, _) = connect(&mut network, "eve");
+        create(&mut network, Room::new("lobby"), &alice);
+        frames.take();
+
+        network.change_room_state("lobby", "slide", Some(&json!(3)), &alice).unwrap();
+        assert_eq!(types(&frames.take()), vec!["state-changed"]);
+        assert_eq!(network.rooms.borrow().get("lobby").unwrap().state.borrow().get("slide"), Some(&json!(3)));
+        network.change_room_state("lobby", "slide", None, &alice).unwrap();
+        assert!(network.rooms.borrow().get("lobby").unwrap().state.borrow().is_empty());
+
+        assert_eq!(network.change_room_state("lobby", "slide", Some(&json!(4)), &eve),
+            Err(SignalError::NotInRoom("lobby".to_string())));
+    }
+
+    #[test]
+    fn room_state_is_limited_in_size() {
+        let mut network = Network::default();
+        network.config.room_state_size = 1;
+        let (alice, _) = connect(&mut network, "alice");
+        create(&mut network, Room::new("lobby"), &alice);
+
+        let key = "k".repeat(MAX_STATE_KEY_SIZE + 1);
+        assert_eq!(network.change_room_state("lobby", &key, None, &alice), Err(SignalError::TooLarge("key")));
+        let value = json!("v".repeat(MAX_STATE_VALUE_SIZE));
+        assert_eq!(network.change_room_state("lobby", "slide", Some(&value), &alice), Err(SignalError::TooLarge("value")));
+
+        network.change_room_state("lobby", "slide", Some(&json!(1)), &alice).unwrap();
+        assert_eq!(network.change_room_state("lobby", "page", Some(&json!(1)), &alice),
+            Err(SignalError::RoomStateFull("lobby".to_string())));
+        assert_eq!(network.change_room_state("lobby", "slide", Some(&json!(2)), &alice), Ok(()));
+    }
+
+    #[test]
+    fn moderators_update_the_room() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        let (bob, _) = connect(&mut network, "bob");
+        create(&mut network, Room::new("lobby"), &alice);
+        network.add_user_to_room("lobby", &bob, None, None, false).unwrap();
+
+        let topic = "Weekly sync".to_string();
+        assert_eq!(network.update_room("lobby", Some(&topic), None, None, &bob),
+            Err(SignalError::NotPermitted("lobby".to_string())));
+        assert_eq!(network.update_room("lobby", Some(&topic), None, Some(true), &alice), Ok(()));
+        assert_eq!(*network.rooms.borrow().get("lobby").unwrap().topic.borrow(), Some(topic));
+
+        let topic = "t".repeat(MAX_TOPIC_SIZE + 1);
+        assert_eq!(network.update_room("lobby", Some(&topic), None, None, &alice), Err(SignalError::TooLarge("topic")));
+        let metadata = json!({"notes": "n".repeat(MAX_METADATA_SIZE)});
+        assert_eq!(network.update_room("lobby", None, Some(&metadata), None, &alice), Err(SignalError::TooLarge("metadata")));
+    }
+
+    fn queued(text: &str, from: &str, id: &str) -> QueuedMessage {
+        QueuedMessage::new(text.to_string(), Some(from.to_string()), Some(id.to_string()), Duration::from_secs(60))
+    }
+
+    #[test]
+    fn queued_messages_are_delivered_on_connect() {
+        let mut network = Network::default();
+        let (_alice, alice_frames) = connect(&mut network, "alice");
+        network.enqueue("bob", queued(r#"{"type":"offer"}"#, "alice", "m1")).unwrap();
+
+        let (bob, bob_frames) = test_node();
+        network.add_user("bob", &bob, None).unwrap();
+        assert!(types(&bob_frames.take()).contains(&"offer"));
+        let receipts = alice_frames.take();
+        assert_eq!(types(&receipts), vec!["ack"]);
+        assert_eq!(receipts[0]["id"], "m1");
+        assert!(network.mailboxes.borrow().is_empty());
+    }
+
+    #[test]
+    fn expired_messages_are_dropped() {
+        let mut network = Network::default();
+        let expired = QueuedMessage::new("{}".to_string(), None, None, Duration::from_secs(0));
+        network.enqueue("bob", expired).unwrap();
+        network.enqueue("eve", queued("{}", "alice", "m1")).unwrap();
+        assert!(!network.mailboxes.borrow().contains_key("bob"));
+    }
+
+    #[test]
+    fn mailboxes_are_limited_per_user_and_in_total() {
+        let mut network = Network::default();
+        network.config.queue_size = 2;
+        network.config.queue_total = 3;
+
+        network.enqueue("bob", queued("{}", "alice", "m1")).unwrap();
+        network.enqueue("bob", queued("{}", "alice", "m2")).unwrap();
+        assert_eq!(network.enqueue("bob", queued("{}", "alice", "m3")),
+            Err(SignalError::QueueFull("bob".to_string())));
+        network.enqueue("eve", queued("{}", "alice", "m4")).unwrap();
+        assert_eq!(network.enqueue("carol", queued("{}", "alice", "m5")),
+            Err(SignalError::QueueFull("carol".to_string())));
+    }
+
+    #[test]
+    fn invites_do_not_cross_tenants() {
+        let base = Network::default();
+        let mut shop = base.configured_like("shop");
+        let blog = base.configured_like("blog");
+        let (alice, _) = connect(&mut shop, "alice");
+        let (eve, _) = connect(&mut shop, "eve");
+        create(&mut shop, private_room("vault"), &alice);
+
+        // Signed over the very room, so only the secret of the tenant tells the invites apart
+        let nonce = shop.rooms.borrow().get("vault").unwrap().nonce.clone();
+        let foreign = Invite::new(&blog.config.invite_secret, "vault", &nonce, blog.config.invite_ttl);
+        assert_eq!(shop.add_user_to_room("vault", &eve, None, Some(&foreign.token), false),
+            Err(SignalError::RoomAccessDenied("vault".to_string())));
+
+        let invite = Invite::new(&shop.config.invite_secret, "vault", &nonce, shop.config.invite_ttl);
+        assert_eq!(shop.add_user_to_room("vault", &eve, None, Some(&invite.token), false), Ok(()));
+    }
+
+    #[test]
+    fn invites_end_with_their_room() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        let (eve, _) = connect(&mut network, "eve");
+        create(&mut network, private_room("vault"), &alice);
+        let revoked = network.create_invite("vault", &alice).unwrap();
+        let unused = network.create_invite("vault", &alice).unwrap();
+        network.revoke_invite("vault", &revoked.id, &alice).unwrap();
+
+        network.remove_user_from_room("vault", &alice).unwrap();
+        assert!(!network.rooms.borrow().contains("vault"));
+        create(&mut network, private_room("vault"), &alice);
+
+        for invite in [revoked, unused].iter() {
+            assert_eq!(network.add_user_to_room("vault", &eve, None, Some(&invite.token), false),
+                Err(SignalError::RoomAccessDenied("vault".to_string())));
+        }
+    }
+
+    #[test]
+    fn banned_users_stop_waiting() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        let (bob, _) = connect(&mut network, "bob");
+        let (eve, _) = connect(&mut network, "eve");
+        let mut room = Room::new("lobby");
+        room.capacity = Some(2);
+        create(&mut network, room, &alice);
+        network.add_user_to_room("lobby", &bob, None, None, false).unwrap();
+
+        network.add_user_to_room("lobby", &eve, None, None, true).unwrap();
+        network.moderate("lobby", Moderation::Ban, &["eve".to_string()], &alice).unwrap();
+        network.remove_user_from_room("lobby", &bob).unwrap();
+        assert!(eve.borrow().rooms.is_empty());
+    }
+
+    #[test]
+    fn users_banned_while_waiting_are_skipped() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        let (bob, _) = connect(&mut network, "bob");
+        let (eve, _) = connect(&mut network, "eve");
+        let mut room = Room::new("lobby");
+        room.capacity = Some(1);
+        create(&mut network, room, &alice);
+
+        network.add_user_to_room("lobby", &eve, None, None, true).unwrap();
+        network.add_user_to_room("lobby", &bob, None, None, true).unwrap();
+        network.rooms.borrow().get("lobby").unwrap().banned.borrow_mut().insert("eve".to_string());
+        network.remove_user_from_room("lobby", &alice).unwrap();
+        assert!(eve.borrow().rooms.is_empty());
+        assert_eq!(bob.borrow().rooms, vec!["lobby".to_string()]);
+    }
+
+    #[test]
+    fn calls_ring_until_the_callee_answers() {
+        let mut network = Network::default();
+        let (alice, alice_frames) = connect(&mut network, "alice");
+        let (bob, bob_frames) = connect(&mut network, "bob");
+
+        network.start_call("bob", json!({"video": true}), &alice).unwrap();
+        let ring = bob_frames.take();
+        assert_eq!(types(&ring), vec!["ring"]);
+        assert_eq!(ring[0]["from"], "alice");
+        assert_eq!(ring[0]["payload"], json!({"video": true}));
+        let ringing = alice_frames.take();
+        assert_eq!(ringing[0]["state"], "ringing");
+
+        let call = ring[0]["call"].as_str().unwrap();
+        network.answer_call(call, CallState::Accepted, &bob).unwrap();
+        assert_eq!(alice_frames.take()[0]["state"], "accepted");
+        assert_eq!(bob_frames.take()[0]["state"], "accepted");
+        assert!(network.calls.borrow().is_empty());
+        assert_eq!(network.answer_call(call, CallState::Rejected, &bob), Err(SignalError::CallNotFound(call.to_string())));
+    }
+
+    #[test]
+    fn only_the_callee_answers_and_only_the_caller_cancels() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        let (bob, bob_frames) = connect(&mut network, "bob");
+        let (eve, _) = connect(&mut network, "eve");
+
+        network.start_call("bob", Value::Null, &alice).unwrap();
+        let call = bob_frames.take()[0]["call"].as_str().unwrap().to_string();
+        let not_found = Err(SignalError::CallNotFound(call.clone()));
+        assert_eq!(network.answer_call(&call, CallState::Accepted, &eve), not_found);
+        assert_eq!(network.answer_call(&call, CallState::Accepted, &alice), not_found);
+        assert_eq!(network.answer_call(&call, CallState::Cancelled, &bob), not_found);
+        assert_eq!(network.answer_call(&call, CallState::Timeout, &bob), not_found);
+        assert_eq!(network.answer_call(&call, CallState::Cancelled, &alice), Ok(()));
+        assert_eq!(bob_frames.take()[0]["state"], "cancelled");
+    }
+
+    #[test]
+    fn users_can_not_call_themselves() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        assert_eq!(network.start_call("alice", Value::Null, &alice), Err(SignalError::CallingSelf));
+        assert!(network.calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn unanswered_calls_time_out() {
+        let mut network = Network::default();
+        let (alice, alice_frames) = connect(&mut network, "alice");
+        let (_bob, bob_frames) = connect(&mut network, "bob");
+
+        let token = network.start_call("bob", Value::Null, &alice).unwrap();
+        alice_frames.take();
+        bob_frames.take();
+        network.time_out_call(token + 1);
+        assert!(bob_frames.take().is_empty());
+        network.time_out_call(token);
+        assert_eq!(alice_frames.take()[0]["state"], "timeout");
+        assert_eq!(bob_frames.take()[0]["state"], "timeout");
+        assert!(network.calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn calls_past_the_ring_timeout_expire() {
+        let mut network = Network::default();
+        network.config.ring_timeout = Duration::from_secs(0);
+        let (alice, _) = connect(&mut network, "alice");
+        let (_bob, bob_frames) = connect(&mut network, "bob");
+        let (eve, _) = connect(&mut network, "eve");
+
+        network.start_call("bob", Value::Null, &alice).unwrap();
+        bob_frames.take();
+        network.start_call("bob", Value::Null, &eve).unwrap();
+        let frames = bob_frames.take();
+        assert_eq!(frames[0]["state"], "timeout");
+        assert_eq!(frames[0]["caller"], "alice");
+    }
+
+    #[test]
+    fn calls_are_cancelled_when_the_caller_disconnects() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        let (_bob, bob_frames) = connect(&mut network, "bob");
+
+        network.start_call("bob", Value::Null, &alice).unwrap();
+        bob_frames.take();
+        network.remove("alice", &alice);
+        assert_eq!(bob_frames.take()[0]["state"], "cancelled");
+        assert!(network.calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn pending_calls_ring_when_the_callee_registers() {
+        let mut network = Network::default();
+        let (_alice, _) = connect(&mut network, "alice");
+        let call = Call {
+            id: "call-1".to_string(),
+            caller: "alice".to_string(),
+            callee: "bob".to_string(),
+            payload: Value::Null,
+            timeout_token: 1,
+            started_at: Instant::now(),
+        };
+        network.calls.borrow_mut().insert(call.id.clone(), call);
+
+        let (bob, frames) = test_node();
+        network.add_user("bob", &bob, None).unwrap();
+        let frames = frames.take();
+        assert_eq!(types(&frames), vec!["welcome", "ring"]);
+        assert_eq!(frames[1]["call"], "call-1");
+    }
+
+    #[test]
+    fn taken_usernames_are_rejected_by_default() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        let (impostor, _) = test_node();
+        assert_eq!(network.add_user("alice", &impostor, None).err(), Some(SignalError::UsernameTaken("alice".to_string())));
+        assert!(network.get_node("alice").is_some_and(|node| Rc::ptr_eq(&node, &alice)));
+    }
+
+    #[test]
+    fn new_connections_replace_old_ones() {
+        let mut network = Network::default();
+        network.config.duplicate_usernames = DuplicatePolicy::ReplaceOld;
+        let (_old, old_connection) = connect(&mut network, "alice");
+        let (new, _) = test_node();
+
+        assert_eq!(network.add_user("alice", &new, None).unwrap().owner, "alice");
+        assert_eq!(old_connection.closed(), Some(CLOSE_REPLACED));
+        assert!(network.get_node("alice").is_some_and(|node| Rc::ptr_eq(&node, &new)));
+    }
+
+    #[test]
+    fn taken_usernames_get_a_suffix() {
+        let mut network = Network::default();
+        network.config.duplicate_usernames = DuplicatePolicy::AutoSuffix;
+        let (_alice, alice_connection) = connect(&mut network, "alice");
+        let (second, _) = test_node();
+        let (third, frames) = test_node();
+
+        assert_eq!(network.add_user("alice", &second, None).unwrap().owner, "alice-2");
+        assert_eq!(network.add_user("alice", &third, None).unwrap().owner, "alice-3");
+        assert_eq!(frames.take()[0]["user"], "alice-3");
+        assert_eq!(alice_connection.closed(), None);
+    }
+
+    /// A network with empty rooms of the names, kept while the test lists them.
+    fn network_with_rooms(names: &[&str]) -> Network {
+        let mut network = Network::default();
+        network.config.room_linger = Duration::from_secs(60);
+        for name in names.iter() {
+            assert!(network.create_room(Room::new(name)));
         }
+        network
     }
-}
\ No newline at end of file
+
+    fn names(page: &Value) -> Vec<&str> {
+        page["rooms"].as_array().unwrap().iter().filter_map(|room| room["name"].as_str()).collect()
+    }
+
+    #[test]
+    fn lists_rooms_by_name_in_pages() {
+        let mut network = network_with_rooms(&["c", "a", "d", "b", "e"]);
+
+        let page: Value = serde_json::from_str(&network.list_rooms(None, None, Some(2), Some("list-1"))).unwrap();
+        assert_eq!(names(&page), vec!["a", "b"]);
+        assert_eq!(page["next"], "b");
+        assert_eq!(page["ref"], "list-1");
+
+        let page: Value = serde_json::from_str(&network.list_rooms(None, Some("b"), Some(2), None)).unwrap();
+        assert_eq!(names(&page), vec!["c", "d"]);
+        let page: Value = serde_json::from_str(&network.list_rooms(None, Some("d"), Some(2), None)).unwrap();
+        assert_eq!(names(&page), vec!["e"]);
+        assert_eq!(page["next"], Value::Null);
+    }
+
+    #[test]
+    fn room_pages_are_limited_in_size() {
+        let all: Vec<String> = (0..MAX_PAGE_SIZE + 1).map(|index| format!("room-{:04}", index)).collect();
+        let mut network = network_with_rooms(&all.iter().map(String::as_str).collect::<Vec<_>>());
+
+        let page: Value = serde_json::from_str(&network.list_rooms(None, None, Some(usize::MAX), None)).unwrap();
+        assert_eq!(names(&page).len(), MAX_PAGE_SIZE);
+        let page: Value = serde_json::from_str(&network.list_rooms(None, None, Some(0), None)).unwrap();
+        assert_eq!(names(&page), vec!["room-0000"]);
+        let page: Value = serde_json::from_str(&network.list_rooms(None, None, None, None)).unwrap();
+        assert_eq!(names(&page).len(), DEFAULT_PAGE_SIZE);
+    }
+
+    #[test]
+    fn lists_rooms_with_the_prefix_that_are_not_hidden() {
+        let mut network = network_with_rooms(&["team-a", "team-b", "lobby", "team-secret"]);
+        network.rooms.borrow().get("team-secret").unwrap().hidden.set(true);
+
+        let page: Value = serde_json::from_str(&network.list_rooms(Some("team-"), None, None, None)).unwrap();
+        assert_eq!(names(&page), vec!["team-a", "team-b"]);
+        let page: Value = serde_json::from_str(&network.list_rooms(Some("nothing"), None, None, None)).unwrap();
+        assert!(names(&page).is_empty());
+    }
+
+    #[test]
+    fn watchers_are_told_the_presence_of_users() {
+        let mut network = Network::default();
+        let (alice, frames) = connect(&mut network, "alice");
+        let (bob, _) = connect(&mut network, "bob");
+        network.set_status(Some("busy".to_string()), &bob).unwrap();
+
+        network.watch(&["bob".to_string(), "carol".to_string()], &alice);
+        let presence = frames.take();
+        assert_eq!(presence[0], json!({"type": "presence", "user": "bob", "online": true, "status": "busy"}));
+        assert_eq!(presence[1], json!({"type": "presence", "user": "carol", "online": false, "status": null}));
+
+        network.watch(&["bob".to_string()], &alice);
+        frames.take();
+        assert_eq!(network.watchers.borrow()["bob"].len(), 1);
+    }
+
+    #[test]
+    fn watchers_are_told_about_changes() {
+        let mut network = Network::default();
+        let (alice, frames) = connect(&mut network, "alice");
+        network.watch(&["bob".to_string()], &alice);
+        frames.take();
+
+        let (bob, _) = connect(&mut network, "bob");
+        assert_eq!(frames.take()[0]["online"], true);
+        network.set_status(Some("away".to_string()), &bob).unwrap();
+        assert_eq!(frames.take()[0]["status"], "away");
+        network.remove("bob", &bob);
+        assert_eq!(frames.take()[0]["online"], false);
+    }
+
+    #[test]
+    fn unwatched_users_are_not_reported() {
+        let mut network = Network::default();
+        let (alice, frames) = connect(&mut network, "alice");
+        network.watch(&["bob".to_string()], &alice);
+        network.unwatch(&["bob".to_string()], &alice);
+        frames.take();
+
+        let (_bob, _) = connect(&mut network, "bob");
+        assert!(frames.take().is_empty());
+        assert!(alice.borrow().watching.is_empty());
+        assert!(network.watchers.borrow().is_empty());
+    }
+
+    #[test]
+    fn watchers_that_disconnect_stop_watching() {
+        let mut network = Network::default();
+        let (alice, _) = connect(&mut network, "alice");
+        network.watch(&["bob".to_string(), "carol".to_string()], &alice);
+        network.remove("alice", &alice);
+        assert!(network.watchers.borrow().is_empty());
+    }
+
+    #[test]
+    fn crossing_offers_are_resolved_with_roles() {
+        let mut network = Network::default();
+        let (_alice, alice_frames) = connect(&mut network, "alice");
+        let (_bob, bob_frames) = connect(&mut network, "bob");
+
+        assert!(network.negotiate("bob", "alice", "offer"));
+        assert!(alice_frames.take().is_empty());
+
+        // Alice sorts first, so she is impolite and her offer is relayed
+        assert!(network.negotiate("alice", "bob", "offer"));
+        assert_eq!(alice_frames.take(), vec![json!({"type": "glare", "peer": "bob", "role": "impolite"})]);
+        assert_eq!(bob_frames.take(), vec![json!({"type": "glare", "peer": "alice", "role": "polite"})]);
+    }
+
+    #[test]
+    fn the_offer_of_the_polite_user_is_dropped() {
+        let mut network = Network::default();
+        let (_alice, alice_frames) = connect(&mut network, "alice");
+        let (_bob, bob_frames) = connect(&mut network, "bob");
+
+        assert!(network.negotiate("alice", "bob", "offer"));
+        assert!(!network.negotiate("bob", "alice", "offer"));
+        assert_eq!(bob_frames.take()[0]["role"], "polite");
+        assert_eq!(alice_frames.take()[0]["role"], "impolite");
+
+        // The offer of Alice is still waiting for an answer, so another offer of Bob loses again
+        assert!(!network.negotiate("bob", "alice", "offer"));
+        assert!(network.negotiate("bob", "alice", "answer"));
+        assert!(network.negotiate("bob", "alice", "offer"));
+    }
+
+    #[test]
+    fn answered_and_rolled_back_offers_do_not_cross() {
+        let mut network = Network::default();
+        let (_alice, alice_frames) = connect(&mut network, "alice");
+
+        assert!(network.negotiate("bob", "alice", "offer"));
+        assert!(network.negotiate("alice", "bob", "answer"));
+        assert!(network.negotiate("alice", "bob", "offer"));
+        assert!(network.negotiate("alice", "bob", "rollback"));
+        assert!(network.negotiate("bob", "alice", "offer"));
+        assert!(alice_frames.take().is_empty());
+    }
+
+    #[test]
+    fn offers_are_not_tracked_without_a_negotiation_window() {
+        let mut network = Network::default();
+        network.config.negotiation_window = Duration::from_secs(0);
+        let (_alice, alice_frames) = connect(&mut network, "alice");
+
+        assert!(network.negotiate("alice", "bob", "offer"));
+        assert!(network.negotiate("bob", "alice", "offer"));
+        assert!(alice_frames.take().is_empty());
+    }
+}
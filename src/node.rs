@@ -1,17 +1,63 @@
+use std::time::{Duration, Instant};
+
+use ws::{CloseCode, Message};
+use ws::util::Token;
+
+/// The connection a node sends its frames on, which is the sender of ws outside of tests.
+pub trait Connection {
+    fn send_message(&self, message: Message) -> ws::Result<()>;
+    fn close_with_reason(&self, code: CloseCode, reason: &str) -> ws::Result<()>;
+    fn timeout(&self, ms: u64, token: Token) -> ws::Result<()>;
+}
+
+impl Connection for ws::Sender {
+    fn send_message(&self, message: Message) -> ws::Result<()> {
+        self.send(message)
+    }
+    fn close_with_reason(&self, code: CloseCode, reason: &str) -> ws::Result<()> {
+        ws::Sender::close_with_reason(self, code, reason.to_string())
+    }
+    fn timeout(&self, ms: u64, token: Token) -> ws::Result<()> {
+        ws::Sender::timeout(self, ms, token)
+    }
+}
+
+impl dyn Connection {
+    pub fn send<M: Into<Message>>(&self, message: M) -> ws::Result<()> {
+        self.send_message(message.into())
+    }
+}
+
 #[cfg(feature = "push")]
 pub struct Node {
     pub owner: Option<String>,
     pub subscription: Option<String>,
-    pub sender: ws::Sender
+    pub sender: Box<dyn Connection>,
+    /// The rooms the node is a member of.
+    pub rooms: Vec<String>,
+    /// The token that lets a new connection resume the node's session.
+    pub resume_token: Option<String>,
+    /// The usernames whose presence the node is subscribed to.
+    pub watching: Vec<String>,
+    /// A custom status shown to the node's watchers, such as away or busy.
+    pub status: Option<String>,
+    window_start: Instant,
+    messages_in_window: u32,
 }
 
 #[cfg(feature = "push")]
 impl Node {
-    pub fn new(sender: ws::Sender) -> Node {
+    pub fn new<C: Connection + 'static>(sender: C) -> Node {
         Node {
             owner: None,
             subscription: None,
-            sender: sender
+            sender: Box::new(sender),
+            rooms: Vec::new(),
+            resume_token: None,
+            watching: Vec::new(),
+            status: None,
+            window_start: Instant::now(),
+            messages_in_window: 0,
         }
     }
 }
@@ -19,15 +65,96 @@ impl Node {
 #[cfg(not(feature = "push"))]
 pub struct Node {
     pub owner: Option<String>,
-    pub sender: ws::Sender
+    pub sender: Box<dyn Connection>,
+    /// The rooms the node is a member of.
+    pub rooms: Vec<String>,
+    /// The token that lets a new connection resume the node's session.
+    pub resume_token: Option<String>,
+    /// The usernames whose presence the node is subscribed to.
+    pub watching: Vec<String>,
+    /// A custom status shown to the node's watchers, such as away or busy.
+    pub status: Option<String>,
+    window_start: Instant,
+    messages_in_window: u32,
 }
 
 #[cfg(not(feature = "push"))]
 impl Node {
-    pub fn new(sender: ws::Sender) -> Node {
+    pub fn new<C: Connection + 'static>(sender: C) -> Node {
         Node {
             owner: None,
-            sender: sender
+            sender: Box::new(sender),
+            rooms: Vec::new(),
+            resume_token: None,
+            watching: Vec::new(),
+            status: None,
+            window_start: Instant::now(),
+            messages_in_window: 0,
+        }
+    }
+}
+
+impl Node {
+    /// Counts a message against a limit of messages per second.
+    /// Returns false if the node has already sent too many messages this second.
+    pub fn within_rate_limit(&mut self, limit: Option<u32>) -> bool {
+        let limit = match limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+
+        if self.window_start.elapsed() >= Duration::from_secs(1) {
+            self.window_start = Instant::now();
+            self.messages_in_window = 0;
         }
+
+        self.messages_in_window += 1;
+        self.messages_in_window <= limit
+    }
+}
+
+/// A connection for tests, that records what is sent on it.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct TestConnection {
+    sent: std::rc::Rc<std::cell::RefCell<Vec<String>>>,
+    closed: std::rc::Rc<std::cell::Cell<Option<u16>>>,
+}
+
+#[cfg(test)]
+impl Connection for TestConnection {
+    fn send_message(&self, message: Message) -> ws::Result<()> {
+        self.sent.borrow_mut().push(message.into_text()?);
+        Ok(())
+    }
+    fn close_with_reason(&self, code: CloseCode, _reason: &str) -> ws::Result<()> {
+        self.closed.set(Some(code.into()));
+        Ok(())
+    }
+    fn timeout(&self, _ms: u64, _token: Token) -> ws::Result<()> {
+        Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+impl TestConnection {
+    /// Takes the frames sent so far.
+    pub fn take(&self) -> Vec<serde_json::Value> {
+        self.sent.borrow_mut().drain(..)
+            .map(|frame| serde_json::from_str(&frame).expect("frames are JSON"))
+            .collect()
+    }
+
+    /// The code the connection was closed with, if it was closed.
+    pub fn closed(&self) -> Option<u16> {
+        self.closed.get()
+    }
+}
+
+/// Creates a node for tests, with the connection its frames are recorded on.
+#[cfg(test)]
+pub fn test_node() -> (std::rc::Rc<std::cell::RefCell<Node>>, TestConnection) {
+    let connection = TestConnection::default();
+    let node = Node::new(connection.clone());
+    (std::rc::Rc::new(std::cell::RefCell::new(node)), connection)
+}
@@ -0,0 +1,136 @@
+//! Parsing of the query string a node connects with,
+//! e.g. `ws://localhost:3012/?user=alice&room=lobby&room=friends`.
+//! Keys may come in any order, values are percent-decoded,
+//! `room` may be repeated and unknown keys are ignored.
+//! A reconnecting node adds `resume=<token>` to resume its session.
+//! When authentication is enabled, the JWT may be given as `token=<jwt>`,
+//! and the username is taken from it instead of `user`.
+//! A node of a configured tenant gives the tenant's API key as `api_key=<key>`.
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Query {
+    pub user: Option<String>,
+    pub rooms: Vec<String>,
+    /// The token of a session the node wants to resume.
+    pub resume: Option<String>,
+    /// The JWT the node authenticates with.
+    pub token: Option<String>,
+    /// The API key of the tenant the node belongs to.
+    pub api_key: Option<String>,
+}
+
+impl Query {
+    /// Parses the query string of a requested resource.
+    /// For backwards compatibility the leading `?` may be left out, as in `/user=alice`.
+    pub fn parse(resource: &str) -> Result<Query, String> {
+        let query_string = match resource.find('?') {
+            Some(index) => &resource[index + 1..],
+            None => resource.trim_start_matches('/'),
+        };
+
+        let mut query = Query::default();
+        for pair in query_string.split('&').filter(|pair| !pair.is_empty()) {
+            let mut parts = pair.splitn(2, '=');
+            let key = percent_decode(parts.next().unwrap_or_default())?;
+            let value = percent_decode(parts.next().unwrap_or_default())?;
+
+            match key.as_str() {
+                "user" if query.user.is_some() => {
+                    return Err("The 'user' parameter is given more than once".to_string())
+                },
+                "user" => query.user = Some(value),
+                "room" => query.rooms.push(value),
+                "resume" => query.resume = Some(value),
+                "token" | "access_token" => query.token = Some(value),
+                "api_key" => query.api_key = Some(value),
+                _ => { /* Unknown parameters are ignored */ }
+            }
+        }
+
+        if query.user.as_ref().is_some_and(String::is_empty) {
+            return Err("The 'user' parameter can not be empty".to_string());
+        }
+
+        if query.rooms.iter().any(String::is_empty) {
+            return Err("A 'room' parameter can not be empty".to_string());
+        }
+
+        Ok(query)
+    }
+}
+
+/// Decodes `%XX` escapes and `+` as used in URL query strings.
+fn percent_decode(input: &str) -> Result<String, String> {
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut iter = input.bytes();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'%' => {
+                let high = iter.next().and_then(hex_value);
+                let low = iter.next().and_then(hex_value);
+                match (high, low) {
+                    (Some(high), Some(low)) => bytes.push(high << 4 | low),
+                    _ => return Err(format!("Invalid percent-encoding in {:?}", input)),
+                }
+            },
+            b'+' => bytes.push(b' '),
+            _ => bytes.push(byte),
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| format!("{:?} is not valid UTF-8 when decoded", input))
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_keys_in_any_order() {
+        let query = Query::parse("/?room=lobby&user=alice&room=friends&resume=abc").unwrap();
+        assert_eq!(query.user.as_deref(), Some("alice"));
+        assert_eq!(query.rooms, vec!["lobby".to_string(), "friends".to_string()]);
+        assert_eq!(query.resume.as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn accepts_a_resource_without_a_question_mark() {
+        let query = Query::parse("/user=alice").unwrap();
+        assert_eq!(query.user.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn decodes_values() {
+        let query = Query::parse("/?user=J%C3%BCrgen+M&room=a%26b").unwrap();
+        assert_eq!(query.user.as_deref(), Some("Jürgen M"));
+        assert_eq!(query.rooms, vec!["a&b".to_string()]);
+    }
+
+    #[test]
+    fn takes_tokens_and_api_keys() {
+        let query = Query::parse("/?access_token=a.b.c&api_key=secret").unwrap();
+        assert_eq!(query.user, None);
+        assert_eq!(query.token.as_deref(), Some("a.b.c"));
+        assert_eq!(query.api_key.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn ignores_unknown_keys() {
+        assert_eq!(Query::parse("/?user=alice&color=blue").unwrap().user.as_deref(), Some("alice"));
+        assert_eq!(Query::parse("/").unwrap(), Query::default());
+    }
+
+    #[test]
+    fn rejects_bad_queries() {
+        assert!(Query::parse("/?user=alice&user=bob").is_err());
+        assert!(Query::parse("/?user=").is_err());
+        assert!(Query::parse("/?user=alice&room=").is_err());
+        assert!(Query::parse("/?user=%zz").is_err());
+        assert!(Query::parse("/?user=%4").is_err());
+        assert!(Query::parse("/?user=%ff").is_err());
+    }
+}
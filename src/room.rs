@@ -2,28 +2,249 @@ use std::rc::Rc;
 use std::rc::Weak;
 use std::cell::RefCell;
 use std::cell::Cell;
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use serde_json::{Map, Value};
+
 use node::Node;
+use session::generate_token;
 
 use std::hash::{Hash, Hasher};
 
+/// The role of a user in a room, from the least to the most privileged.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum RoomRole {
+    Member,
+    /// Can invite users, and kick, ban and mute members.
+    Moderator,
+    /// Can also promote and demote moderators, and transfer the ownership.
+    /// The user that creates a room owns it.
+    Owner,
+}
+
+/// A command a moderator or the owner gives to manage the members of a room.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Moderation {
+    /// Removes the user from the room.
+    Kick,
+    /// Removes the user from the room, and keeps it from entering again.
+    Ban,
+    Unban,
+    /// Keeps the user from sending messages to the room.
+    Mute,
+    Unmute,
+    /// Makes the user a moderator.
+    Promote,
+    Demote,
+    /// Makes the user the owner, and the previous owner a moderator.
+    TransferOwnership,
+}
+
+impl Moderation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Moderation::Kick => "kick",
+            Moderation::Ban => "ban",
+            Moderation::Unban => "unban",
+            Moderation::Mute => "mute",
+            Moderation::Unmute => "unmute",
+            Moderation::Promote => "promote",
+            Moderation::Demote => "demote",
+            Moderation::TransferOwnership => "transfer-ownership",
+        }
+    }
+
+    /// The role a user needs to give the command.
+    pub fn required_role(self) -> RoomRole {
+        match self {
+            Moderation::Kick | Moderation::Ban | Moderation::Unban
+                | Moderation::Mute | Moderation::Unmute => RoomRole::Moderator,
+            Moderation::Promote | Moderation::Demote | Moderation::TransferOwnership => RoomRole::Owner,
+        }
+    }
+}
+
+/// The largest metadata a room can have, in bytes of JSON.
+pub const MAX_METADATA_SIZE: usize = 16 * 1024;
+/// The longest topic a room can have, in bytes.
+pub const MAX_TOPIC_SIZE: usize = 1024;
+/// The longest key of the shared state, in bytes.
+pub const MAX_STATE_KEY_SIZE: usize = 256;
+/// The largest value a key of the shared state can have, in bytes of JSON.
+pub const MAX_STATE_VALUE_SIZE: usize = 4 * 1024;
+
 #[derive(Default)]
 pub struct Room {
     pub name: String,
     pub nodes: Rc<RefCell<Vec<Weak<RefCell<Node>>>>>,
+    /// When the last member left the room, if it is empty.
+    empty_since: Cell<Option<Instant>>,
+    /// The user that owns the room, initially the user that created it.
+    pub owner: RefCell<Option<String>>,
+    pub moderators: RefCell<HashSet<String>>,
+    /// Users that may not enter the room.
+    pub banned: RefCell<HashSet<String>>,
+    /// Users that may not send messages to the room.
+    pub muted: RefCell<HashSet<String>>,
+    /// The hash of the password that lets users enter the room, if any.
+    pub password_hash: Option<String>,
+    /// Only users with an invite can enter the room.
+    pub invite_only: bool,
+    /// A random value invites to the room are signed over, so that they, and their revocations,
+    /// end with the room instead of carrying over to a room created later under the same name.
+    pub nonce: String,
+    /// The ids of invites that no longer let anyone enter the room.
+    pub revoked_invites: RefCell<HashSet<String>>,
+    /// The users that entered the private room before, and may enter again
+    /// without a password or invite, e.g. when resuming their session.
+    pub admitted: RefCell<HashSet<String>>,
+    /// The most users that can be in the room at once, if limited.
+    pub capacity: Option<usize>,
+    /// Nodes waiting for a place in the full room, in the order they asked to join.
+    waiting: RefCell<VecDeque<Weak<RefCell<Node>>>>,
+    /// What the room is about, set by the owner and the moderators.
+    pub topic: RefCell<Option<String>>,
+    /// Arbitrary JSON describing the room, set by the owner and the moderators.
+    pub metadata: RefCell<Value>,
+    /// Hidden rooms are not listed, and can only be found by their name.
+    pub hidden: Cell<bool>,
+    /// A small key-value store shared by the members,
+    /// e.g. to tell who is sharing their screen.
+    pub state: RefCell<Map<String, Value>>,
 }
 
 impl Room {
     pub fn new(name: &str) -> Room {
         Room {
             name: name.to_string(),
-            nodes: Rc::new(RefCell::new(Vec::new()))
+            nodes: Rc::new(RefCell::new(Vec::new())),
+            empty_since: Cell::new(None),
+            owner: RefCell::new(None),
+            moderators: RefCell::new(HashSet::new()),
+            banned: RefCell::new(HashSet::new()),
+            muted: RefCell::new(HashSet::new()),
+            password_hash: None,
+            invite_only: false,
+            nonce: generate_token(),
+            revoked_invites: RefCell::new(HashSet::new()),
+            admitted: RefCell::new(HashSet::new()),
+            capacity: None,
+            waiting: RefCell::new(VecDeque::new()),
+            topic: RefCell::new(None),
+            metadata: RefCell::new(Value::Null),
+            hidden: Cell::new(false),
+            state: RefCell::new(Map::new()),
+        }
+    }
+
+    /// The role of the user in the room.
+    pub fn role_of(&self, user: &str) -> RoomRole {
+        if self.owner.borrow().as_deref() == Some(user) {
+            RoomRole::Owner
+        } else if self.moderators.borrow().contains(user) {
+            RoomRole::Moderator
+        } else {
+            RoomRole::Member
+        }
+    }
+
+    /// Checks if the room has no place for the node's user.
+    /// A user that is already in the room, with another connection, always has a place.
+    pub fn is_full(&self, node: &std::rc::Rc<std::cell::RefCell<Node>>) -> bool {
+        match self.capacity {
+            Some(capacity) => {
+                let members = self.member_names(node);
+                let is_member = node.borrow().owner.as_ref().is_some_and(|owner| members.contains(owner));
+                !is_member && members.len() >= capacity
+            },
+            None => false,
+        }
+    }
+
+    /// Puts the node on the waiting list, unless it is already waiting,
+    /// and returns its position in the list, starting at 1.
+    pub fn wait(&self, node: &std::rc::Rc<std::cell::RefCell<Node>>) -> usize {
+        let node = Rc::downgrade(node);
+        let mut waiting = self.waiting.borrow_mut();
+        waiting.retain(|waiting| waiting.upgrade().is_some());
+        match waiting.iter().position(|waiting| waiting.ptr_eq(&node)) {
+            Some(index) => index + 1,
+            None => {
+                waiting.push_back(node);
+                waiting.len()
+            }
+        }
+    }
+
+    /// Takes the node off the waiting list. Returns false if it was not waiting.
+    pub fn stop_waiting(&self, node: &std::rc::Rc<std::cell::RefCell<Node>>) -> bool {
+        let node = Rc::downgrade(node);
+        let mut waiting = self.waiting.borrow_mut();
+        let length = waiting.len();
+        waiting.retain(|waiting| !waiting.ptr_eq(&node));
+        waiting.len() != length
+    }
+
+    /// Takes every node of the user off the waiting list, e.g. when it is banned.
+    pub fn stop_waiting_user(&self, user: &str) {
+        self.waiting.borrow_mut().retain(|waiting| {
+            waiting.upgrade().is_some_and(|node| node.borrow().owner.as_deref() != Some(user))
+        });
+    }
+
+    /// Takes the next node off the waiting list, if it is still connected and there is a place for it.
+    /// Nodes of users that were banned while waiting are skipped.
+    pub fn next_waiting(&self) -> Option<Rc<RefCell<Node>>> {
+        let mut waiting = self.waiting.borrow_mut();
+        while let Some(next) = waiting.front().map(Weak::upgrade) {
+            let banned = next.as_ref()
+                .and_then(|node| node.borrow().owner.clone())
+                .is_some_and(|owner| self.banned.borrow().contains(&owner));
+            match next {
+                Some(_) if banned => { waiting.pop_front(); },
+                Some(node) if self.is_full(&node) => return None,
+                Some(node) => {
+                    waiting.pop_front();
+                    return Some(node);
+                },
+                None => { waiting.pop_front(); },
+            }
         }
-    } 
+        None
+    }
+
+    /// The number of users in the room.
+    pub fn member_count(&self) -> usize {
+        let mut names: Vec<String> = self.members().iter()
+            .filter_map(|member| member.borrow().owner.clone())
+            .collect();
+        names.sort();
+        names.dedup();
+        names.len()
+    }
 
-    pub fn add_node(&mut self, node: &std::rc::Rc<std::cell::RefCell<Node>>) {
+    /// Checks if the node is a member of the room.
+    pub fn has_member(&self, node: &std::rc::Rc<std::cell::RefCell<Node>>) -> bool {
+        let node = Rc::downgrade(node);
+        self.nodes.borrow().iter().any(|member| member.ptr_eq(&node))
+    }
+
+    /// Checks if users need a password or an invite to enter the room.
+    pub fn is_private(&self) -> bool {
+        self.password_hash.is_some() || self.invite_only
+    }
+
+    pub fn add_node(&self, node: &std::rc::Rc<std::cell::RefCell<Node>>) {
         self.nodes.borrow_mut().push(Rc::downgrade(node));
     }
 
+    pub fn remove_node(&self, node: &std::rc::Rc<std::cell::RefCell<Node>>) {
+        let node = Rc::downgrade(node);
+        self.nodes.borrow_mut().retain(|member| !member.ptr_eq(&node));
+    }
+
+    #[allow(dead_code)]
     pub fn print_nodes(&self) {
        for node in self.nodes.borrow().iter() {
             match node.upgrade() {
@@ -32,6 +253,48 @@ impl Room {
             };
        }
     }
+
+    /// The members of the room that are still connected.
+    pub fn members(&self) -> Vec<Rc<RefCell<Node>>> {
+        self.nodes.borrow().iter().filter_map(|member| member.upgrade()).collect()
+    }
+
+    /// The usernames of the members, except for the given node.
+    /// A user is listed once, even while a resumed session has two connections in the room.
+    pub fn member_names(&self, except: &std::rc::Rc<std::cell::RefCell<Node>>) -> Vec<String> {
+        let mut names: Vec<String> = self.members().iter()
+            .filter(|member| !Rc::ptr_eq(member, except))
+            .filter_map(|member| member.borrow().owner.clone())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Sends a message to every member of the room, except for the given node.
+    pub fn broadcast(&self, message: &str, except: &std::rc::Rc<std::cell::RefCell<Node>>) {
+        for member in self.members().iter().filter(|member| !Rc::ptr_eq(member, except)) {
+            member.borrow().sender.send(message).ok();
+        }
+    }
+
+    /// Forgets members that have disconnected.
+    pub fn prune(&self) {
+        self.nodes.borrow_mut().retain(|member| member.upgrade().is_some());
+    }
+
+    /// Checks if the room has been empty for at least the linger time,
+    /// meaning it can be removed from the network.
+    pub fn has_lingered(&self, linger: Duration) -> bool {
+        if !self.nodes.borrow().is_empty() {
+            self.empty_since.set(None);
+            return false;
+        }
+
+        let empty_since = self.empty_since.get().unwrap_or_else(Instant::now);
+        self.empty_since.set(Some(empty_since));
+        empty_since.elapsed() >= linger
+    }
 }
 
 impl Hash for Room {
@@ -0,0 +1,418 @@
+//! Validation of the session descriptions and ICE candidates peers send each other.
+//! Without it the server relays whatever text it receives, so malformed or huge
+//! descriptions propagate straight to the peers.
+//!
+//! A signaling message may carry them anywhere in its payload or extra fields:
+//! strings under an `sdp`, `offer` or `answer` key are session descriptions (RFC 4566),
+//! and strings under a `candidate` key are ICE candidates (RFC 8839).
+//! Private and host candidates can be stripped, so peers never learn the local
+//! addresses of each other. The addresses of the `c=` and `a=rtcp:` lines are hidden along with them.
+
+use std::net::IpAddr;
+
+use serde_json::{Map, Value};
+
+use config::Config;
+use message::Envelope;
+
+/// Candidates are a single line, anything longer than this is not a real candidate.
+const MAX_CANDIDATE_LENGTH: usize = 1024;
+
+/// The line types a session description may contain.
+const SDP_LINE_TYPES: &str = "vosiuepcbtrzkam";
+
+const CANDIDATE_TYPES: [&str; 4] = ["host", "srflx", "prflx", "relay"];
+
+/// What should happen to a message, or a value in it, after it has been sanitized.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Relay,
+    /// Everything the message or value carried was stripped, so there is nothing to relay.
+    Drop,
+}
+
+/// Validates the session descriptions and candidates in the message,
+/// stripping private candidates from it if configured to.
+/// Only the stripped candidates are removed, the message is dropped if nothing else is left.
+pub fn sanitize(envelope: &mut Envelope, config: &Config) -> Result<Verdict, String> {
+    let mut stripped = false;
+    if sanitize_value(&mut envelope.payload, config)? == Verdict::Drop {
+        envelope.payload = Value::Null;
+        stripped = true;
+    }
+    if sanitize_object(&mut envelope.extra, config)? == Verdict::Drop {
+        envelope.extra.clear();
+        stripped = true;
+    }
+
+    if stripped && envelope.payload.is_null() && envelope.extra.is_empty() {
+        Ok(Verdict::Drop)
+    } else {
+        Ok(Verdict::Relay)
+    }
+}
+
+/// Sanitizes a value, removing the stripped candidates from its arrays and objects.
+/// Returns Drop if the value should be removed, because it only held stripped candidates.
+fn sanitize_value(value: &mut Value, config: &Config) -> Result<Verdict, String> {
+    match value {
+        Value::Object(object) => sanitize_object(object, config),
+        Value::Array(values) => {
+            let length = values.len();
+            let mut verdicts = Vec::with_capacity(length);
+            for value in values.iter_mut() {
+                verdicts.push(sanitize_value(value, config)?);
+            }
+            let mut verdicts = verdicts.into_iter();
+            values.retain(|_| verdicts.next() == Some(Verdict::Relay));
+            Ok(if length > 0 && values.is_empty() { Verdict::Drop } else { Verdict::Relay })
+        },
+        _ => Ok(Verdict::Relay),
+    }
+}
+
+/// Sanitizes an object. An object with a stripped candidate is the candidate,
+/// e.g. `{"candidate": "...", "sdpMid": "0"}`, so all of it is dropped.
+fn sanitize_object(object: &mut Map<String, Value>, config: &Config) -> Result<Verdict, String> {
+    let length = object.len();
+    let mut dropped = Vec::new();
+    for (key, value) in object.iter_mut() {
+        let replacement = match (key.as_str(), &*value) {
+            ("sdp", Value::String(sdp)) | ("offer", Value::String(sdp)) | ("answer", Value::String(sdp)) => {
+                Value::String(sanitize_sdp(sdp, config)?)
+            },
+            ("candidate", Value::String(candidate)) => {
+                match sanitize_candidate(candidate, config.strip_private_candidates)? {
+                    Some(candidate) => Value::String(candidate),
+                    None => return Ok(Verdict::Drop),
+                }
+            },
+            _ => {
+                if sanitize_value(value, config)? == Verdict::Drop {
+                    dropped.push(key.clone());
+                }
+                continue;
+            }
+        };
+        *value = replacement;
+    }
+
+    for key in dropped.iter() {
+        object.remove(key);
+    }
+    Ok(if length > 0 && object.is_empty() { Verdict::Drop } else { Verdict::Relay })
+}
+
+/// Checks the structure of a session description and returns it with
+/// normalized line endings, and without the candidates that are stripped.
+fn sanitize_sdp(sdp: &str, config: &Config) -> Result<String, String> {
+    if sdp.len() > config.max_sdp_size {
+        return Err(format!("The session description is larger than {} bytes", config.max_sdp_size));
+    }
+
+    let mut sanitized = String::with_capacity(sdp.len());
+    let mut session_lines = String::new();
+    let mut in_media = false;
+    for (index, line) in sdp.lines().enumerate() {
+        let number = index + 1;
+        let bytes = line.as_bytes();
+        if bytes.len() < 2 || bytes[1] != b'=' || !SDP_LINE_TYPES.contains(bytes[0] as char) {
+            return Err(format!("Line {} of the session description is not a valid <type>=<value> line", number));
+        }
+        let (kind, value) = (bytes[0] as char, &line[2..]);
+
+        match kind {
+            'v' if number == 1 && value == "0" => {},
+            'v' => return Err("The session description must start with v=0".to_string()),
+            _ if number == 1 => return Err("The session description must start with v=0".to_string()),
+            'm' => {
+                validate_media(value).map_err(|reason| format!("Line {}: {}", number, reason))?;
+                in_media = true;
+            },
+            'a' if value.starts_with("candidate:") => {
+                let candidate = sanitize_candidate(value, config.strip_private_candidates)
+                    .map_err(|reason| format!("Line {}: {}", number, reason))?;
+                if let Some(candidate) = candidate {
+                    sanitized.push_str("a=");
+                    sanitized.push_str(&candidate);
+                    sanitized.push_str("\r\n");
+                }
+                continue;
+            },
+            _ if !in_media => session_lines.push(kind),
+            _ => {},
+        }
+
+        match hidden_address(kind, value) {
+            Some(hidden) if config.strip_private_candidates => sanitized.push_str(&hidden),
+            _ => sanitized.push_str(line),
+        }
+        sanitized.push_str("\r\n");
+    }
+
+    for required in ['o', 's', 't'].iter() {
+        if !session_lines.contains(*required) {
+            return Err(format!("The session description has no {}= line", required));
+        }
+    }
+
+    Ok(sanitized)
+}
+
+/// A `c=` or `a=rtcp:` line with its address replaced by the unspecified address of its family,
+/// since the address is that of a candidate, which may be private,
+/// e.g. `c=IN IP4 192.168.1.7` becomes `c=IN IP4 0.0.0.0`, and `c=IN IP6 fd00::2` becomes `c=IN IP6 ::`.
+fn hidden_address(kind: char, value: &str) -> Option<String> {
+    let unspecified = |address: &str| if address.contains("IN IP6 ") { "IN IP6 ::" } else { "IN IP4 0.0.0.0" };
+    match kind {
+        'c' => Some(format!("c={}", unspecified(value))),
+        'a' if value.starts_with("rtcp:") && value.contains(' ') => {
+            let port = value["rtcp:".len()..].split(' ').next().unwrap_or_default();
+            Some(format!("a=rtcp:{} {}", port, unspecified(value)))
+        },
+        _ => None,
+    }
+}
+
+/// Checks a media line, e.g. `audio 9 UDP/TLS/RTP/SAVPF 111 0`.
+fn validate_media(value: &str) -> Result<(), String> {
+    let fields: Vec<&str> = value.split(' ').collect();
+    if fields.len() < 4 || fields.iter().any(|field| field.is_empty()) {
+        return Err("A media line needs a media type, port, protocol and formats".to_string());
+    }
+
+    let mut port = fields[1].splitn(2, '/');
+    let valid_port = port.next().is_some_and(|port| port.parse::<u16>().is_ok())
+        && port.next().is_none_or(|count| count.parse::<u16>().is_ok());
+    if !valid_port {
+        return Err(format!("{:?} is not a valid media port", fields[1]));
+    }
+    Ok(())
+}
+
+/// Checks a candidate, e.g. `candidate:842163049 1 udp 1677729535 203.0.113.7 46154 typ srflx raddr 0.0.0.0 rport 0`.
+/// Returns None if the candidate is stripped. An empty candidate marks the end of
+/// the candidates and is always relayed.
+fn sanitize_candidate(candidate: &str, strip_private: bool) -> Result<Option<String>, String> {
+    if candidate.is_empty() {
+        return Ok(Some(String::new()));
+    }
+    if candidate.len() > MAX_CANDIDATE_LENGTH {
+        return Err(format!("The candidate is larger than {} bytes", MAX_CANDIDATE_LENGTH));
+    }
+
+    let attribute = candidate.trim_start_matches("a=");
+    let prefix = &candidate[..candidate.len() - attribute.len()];
+    if !attribute.starts_with("candidate:") {
+        return Err("A candidate must start with 'candidate:'".to_string());
+    }
+    let mut fields: Vec<String> = attribute["candidate:".len()..]
+        .split_whitespace()
+        .map(String::from)
+        .collect();
+
+    if fields.len() < 8 || !fields.len().is_multiple_of(2) {
+        return Err("A candidate needs a foundation, component, transport, priority, \
+            address, port and type".to_string());
+    }
+    let valid_foundation = fields[0].len() <= 32
+        && fields[0].chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/');
+    if !valid_foundation {
+        return Err(format!("{:?} is not a valid candidate foundation", fields[0]));
+    }
+    if !fields[1].parse::<u16>().is_ok_and(|component| (1..=256).contains(&component)) {
+        return Err(format!("{:?} is not a valid candidate component", fields[1]));
+    }
+    if !fields[2].chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("{:?} is not a valid candidate transport", fields[2]));
+    }
+    if fields[3].parse::<u32>().is_err() {
+        return Err(format!("{:?} is not a valid candidate priority", fields[3]));
+    }
+    if fields[5].parse::<u16>().is_err() {
+        return Err(format!("{:?} is not a valid candidate port", fields[5]));
+    }
+    if fields[6] != "typ" || !CANDIDATE_TYPES.contains(&fields[7].as_str()) {
+        return Err("A candidate needs a type of host, srflx, prflx or relay".to_string());
+    }
+
+    if strip_private {
+        if fields[7] == "host" || is_private(&fields[4]) {
+            return Ok(None);
+        }
+        // The related address of a reflexive candidate is the private address behind it
+        for index in (8..fields.len()).step_by(2) {
+            match fields[index].as_str() {
+                "raddr" => fields[index + 1] = "0.0.0.0".to_string(),
+                "rport" => fields[index + 1] = "0".to_string(),
+                _ => {},
+            }
+        }
+    }
+
+    Ok(Some(format!("{}candidate:{}", prefix, fields.join(" "))))
+}
+
+/// Checks if an address is only reachable from the local network.
+/// mDNS hostnames such as `1f4712db.local` are private too.
+fn is_private(address: &str) -> bool {
+    match address.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified(),
+        Ok(IpAddr::V6(ip)) => {
+            let first = ip.segments()[0];
+            ip.is_loopback() || ip.is_unspecified()
+                || first & 0xfe00 == 0xfc00 // Unique local
+                || first & 0xffc0 == 0xfe80 // Link local
+        },
+        Err(_) => address.ends_with(".local"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER: &str = "v=0\r\n\
+        o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n\
+        s=-\r\n\
+        t=0 0\r\n\
+        m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+        c=IN IP4 192.168.1.7\r\n\
+        a=rtcp:9 IN IP4 192.168.1.7\r\n\
+        a=candidate:1 1 udp 2122260223 192.168.1.7 54321 typ host\r\n\
+        a=candidate:2 1 udp 1686052607 203.0.113.7 46154 typ srflx raddr 192.168.1.7 rport 54321\r\n\
+        a=rtpmap:111 opus/48000/2\r\n";
+
+    fn config(strip_private_candidates: bool) -> Config {
+        Config {
+            validate_sdp: true,
+            strip_private_candidates,
+            ..Config::default()
+        }
+    }
+
+    fn envelope(payload: Value) -> Envelope {
+        Envelope { payload, ..Envelope::default() }
+    }
+
+    #[test]
+    fn relays_a_valid_description_untouched() {
+        let sanitized = sanitize_sdp(OFFER, &config(false)).unwrap();
+        assert_eq!(sanitized, OFFER);
+    }
+
+    #[test]
+    fn normalizes_line_endings() {
+        let sdp = OFFER.replace("\r\n", "\n");
+        assert_eq!(sanitize_sdp(&sdp, &config(false)).unwrap(), OFFER);
+    }
+
+    #[test]
+    fn strips_private_candidates_and_addresses() {
+        let sanitized = sanitize_sdp(OFFER, &config(true)).unwrap();
+        assert!(!sanitized.contains("192.168.1.7"));
+        assert!(!sanitized.contains("typ host"));
+        assert!(sanitized.contains("c=IN IP4 0.0.0.0\r\n"));
+        assert!(sanitized.contains("a=rtcp:9 IN IP4 0.0.0.0\r\n"));
+        assert!(sanitized.contains("203.0.113.7 46154 typ srflx raddr 0.0.0.0 rport 0\r\n"));
+    }
+
+    #[test]
+    fn rejects_malformed_descriptions() {
+        let config = config(false);
+        assert!(sanitize_sdp("", &config).is_err());
+        assert!(sanitize_sdp("o=- 1 2 IN IP4 127.0.0.1\r\nv=0\r\n", &config).is_err());
+        assert!(sanitize_sdp(&OFFER.replace("s=-\r\n", ""), &config).is_err());
+        assert!(sanitize_sdp(&OFFER.replace("m=audio 9", "m=audio nine"), &config).is_err());
+        assert!(sanitize_sdp(&OFFER.replace("a=rtpmap", "rtpmap"), &config).is_err());
+        assert!(sanitize_sdp(&OFFER.replace("a=rtpmap", "x=rtpmap"), &config).is_err());
+        assert!(sanitize_sdp(&OFFER.replace("typ host", "typ nat"), &config).is_err());
+    }
+
+    #[test]
+    fn rejects_descriptions_that_are_too_large() {
+        let config = Config { max_sdp_size: 64, ..config(false) };
+        assert!(sanitize_sdp(OFFER, &config).is_err());
+    }
+
+    #[test]
+    fn validates_candidates() {
+        let candidate = "candidate:842163049 1 udp 1677729535 203.0.113.7 46154 typ srflx raddr 0.0.0.0 rport 0";
+        assert_eq!(sanitize_candidate(candidate, false).unwrap().as_deref(), Some(candidate));
+        assert_eq!(sanitize_candidate("", false).unwrap().as_deref(), Some(""));
+
+        assert!(sanitize_candidate("842163049 1 udp 1677729535 203.0.113.7 46154 typ srflx", false).is_err());
+        assert!(sanitize_candidate("candidate:1 1 udp 1677729535 203.0.113.7 46154 typ", false).is_err());
+        assert!(sanitize_candidate("candidate:1 0 udp 1677729535 203.0.113.7 46154 typ srflx", false).is_err());
+        assert!(sanitize_candidate("candidate:1 1 udp high 203.0.113.7 46154 typ srflx", false).is_err());
+        assert!(sanitize_candidate("candidate:1 1 udp 1677729535 203.0.113.7 99999 typ srflx", false).is_err());
+        assert!(sanitize_candidate(&format!("candidate:{}", "1".repeat(MAX_CANDIDATE_LENGTH)), false).is_err());
+    }
+
+    #[test]
+    fn strips_private_and_mdns_candidates() {
+        for address in ["10.0.0.2", "172.16.4.1", "127.0.0.1", "fe80::1", "fd00::2", "1f4712db.local"].iter() {
+            let candidate = format!("candidate:1 1 udp 1677729535 {} 46154 typ srflx", address);
+            assert_eq!(sanitize_candidate(&candidate, true).unwrap(), None, "{}", address);
+        }
+        let public = "candidate:1 1 udp 1677729535 2001:db8::7 46154 typ srflx";
+        assert!(sanitize_candidate(public, true).unwrap().is_some());
+    }
+
+    #[test]
+    fn drops_messages_that_only_carry_stripped_candidates() {
+        let mut host = envelope(json!({"candidate": "candidate:1 1 udp 2122260223 192.168.1.7 54321 typ host"}));
+        assert_eq!(sanitize(&mut host, &config(true)), Ok(Verdict::Drop));
+
+        let mut relay = envelope(json!({"candidate": {"candidate": "candidate:3 1 udp 41885439 198.51.100.4 3478 typ relay"}}));
+        assert_eq!(sanitize(&mut relay, &config(true)), Ok(Verdict::Relay));
+    }
+
+    #[test]
+    fn sanitizes_descriptions_anywhere_in_the_message() {
+        let mut offer = envelope(json!({"description": {"type": "offer", "sdp": OFFER}}));
+        assert_eq!(sanitize(&mut offer, &config(true)), Ok(Verdict::Relay));
+        assert!(!offer.payload["description"]["sdp"].as_str().unwrap().contains("192.168.1.7"));
+
+        let mut invalid = envelope(Value::Null);
+        invalid.extra.insert("answer".to_string(), json!("not a description"));
+        assert!(sanitize(&mut invalid, &config(false)).is_err());
+    }
+
+    #[test]
+    fn hides_addresses_in_their_own_family() {
+        let sdp = OFFER.replace("IN IP4 192.168.1.7", "IN IP6 fd00::2");
+        let sanitized = sanitize_sdp(&sdp, &config(true)).unwrap();
+        assert!(!sanitized.contains("fd00::2"));
+        assert!(sanitized.contains("c=IN IP6 ::\r\n"));
+        assert!(sanitized.contains("a=rtcp:9 IN IP6 ::\r\n"));
+    }
+
+    #[test]
+    fn removes_only_the_stripped_candidates_of_a_batch() {
+        let host = json!({"candidate": "candidate:1 1 udp 2122260223 192.168.1.7 54321 typ host", "sdpMid": "0"});
+        let relay = json!({"candidate": "candidate:3 1 udp 41885439 198.51.100.4 3478 typ relay", "sdpMid": "0"});
+        let mut batch = envelope(json!({"candidates": [host, relay], "ufrag": "x9Kd"}));
+        assert_eq!(sanitize(&mut batch, &config(true)), Ok(Verdict::Relay));
+        assert_eq!(batch.payload, json!({"candidates": [relay], "ufrag": "x9Kd"}));
+
+        let mut array = envelope(json!([host, relay]));
+        assert_eq!(sanitize(&mut array, &config(true)), Ok(Verdict::Relay));
+        assert_eq!(array.payload, json!([relay]));
+
+        let mut hosts = envelope(json!([host, host]));
+        assert_eq!(sanitize(&mut hosts, &config(true)), Ok(Verdict::Drop));
+    }
+
+    #[test]
+    fn keeps_the_rest_of_a_message_with_a_stripped_candidate() {
+        let mut message = envelope(json!({"candidate": "candidate:1 1 udp 2122260223 192.168.1.7 54321 typ host"}));
+        message.extra.insert("label".to_string(), json!("camera"));
+        assert_eq!(sanitize(&mut message, &config(true)), Ok(Verdict::Relay));
+        assert_eq!(message.payload, Value::Null);
+        assert_eq!(message.extra.get("label"), Some(&json!("camera")));
+
+        let mut empty = envelope(Value::Null);
+        assert_eq!(sanitize(&mut empty, &config(true)), Ok(Verdict::Relay));
+    }
+}
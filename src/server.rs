@@ -6,9 +6,13 @@ use std::thread::sleep;
 #[cfg(feature = "ssl")]
 use std::time::Duration;
 
+#[cfg(feature = "push")]
 use serde_json::Value;
 
-use ws::{listen, Handler, Result, Message, Handshake, CloseCode};
+use ws::{Handler, Result, Message, Handshake, CloseCode, Request, Response};
+#[cfg(not(feature = "ssl"))]
+use ws::listen;
+use ws::util::Token;
 #[cfg(feature = "ssl")]
 use ws::util::TcpStream;
 
@@ -26,146 +30,516 @@ use openssl::x509::X509;
 
 use node::Node;
 use network::Network;
+use message::{Delivery, Envelope, MessageType, Protocol, ack_frame, ice_servers_frame, invite_frame, nack_frame,
+    room_state_frame};
+use room::{Room, MAX_METADATA_SIZE, MAX_TOPIC_SIZE};
+use access::hash_password;
+use error::{DeliveryResult, SignalError, SignalResult, CLOSE_USERNAME_TAKEN};
+use mailbox::QueuedMessage;
+use call::CallState;
+use turn;
+use stun;
+use sdp::{self, Verdict};
+use negotiation;
+use auth::{self, Authenticator};
+use config::{Config, DuplicatePolicy};
+use query::Query;
+use tenant::{Tenants, DEFAULT_TENANT};
 
 #[cfg(feature = "ssl")]
 struct Server {
     node: Rc<RefCell<Node>>,
     ssl: Rc<SslAcceptor>,
+    /// The network of the node's tenant, picked when the handshake is requested.
     network: Rc<RefCell<Network>>,
+    tenants: Rc<RefCell<Tenants>>,
+    /// The name of the node's tenant.
+    tenant: String,
+    /// Whether the handshake reserved one of the tenant's connections, which is released when the server is dropped.
+    holds_connection: bool,
+    query: Query,
 }
 
 #[cfg(not(feature = "ssl"))]
 struct Server {
     node: Rc<RefCell<Node>>,
+    /// The network of the node's tenant, picked when the handshake is requested.
     network: Rc<RefCell<Network>>,
+    tenants: Rc<RefCell<Tenants>>,
+    /// The name of the node's tenant.
+    tenant: String,
+    /// Whether the handshake reserved one of the tenant's connections, which is released when the server is dropped.
+    holds_connection: bool,
+    query: Query,
 }
 
 impl Server {
+    /// Reports an error to the node, referring to the message that caused it.
+    fn send_error(&self, error: &SignalError, reference: Option<&str>) -> Result<()> {
+        self.node.borrow().sender.send(error.to_frame(reference))
+    }
+
+    /// Tells the node how its message was handled.
+    /// Messages with an id are acknowledged, or negatively acknowledged if they could not be delivered.
+    fn send_outcome(&self, envelope: &Envelope, result: DeliveryResult) -> Result<()> {
+        let sender = &self.node.borrow().sender;
+        match (result, envelope.reference()) {
+            (Ok(delivery), Some(id)) => sender.send(ack_frame(id, delivery)),
+            (Ok(_), None) => Ok(()),
+            (Err(error), Some(id)) => match error.nack_reason() {
+                Some(reason) => sender.send(nack_frame(id, reason)),
+                None => sender.send(error.to_frame(Some(id))),
+            },
+            (Err(error), None) => sender.send(error.to_frame(None)),
+        }
+    }
+
+    /// Handles the push actions of a message. Returns None if the message has no push action,
+    /// so it is handled like any other message.
     #[cfg(feature = "push")]       
-    fn handle_push_requests(&mut self, json_message: &Value) {  
-        match json_message["action"].as_str() {
+    fn handle_push_requests(&mut self, envelope: &Envelope) -> Option<SignalResult> {  
+        match envelope.extra.get("action").and_then(Value::as_str) {
             Some("subscribe-push") => { 
-                    match json_message["subscriptionData"].as_str() {
+                    match envelope.extra.get("subscriptionData").and_then(Value::as_str) {
                             Some(data) => {
-                                self.network.borrow_mut().add_subscription(data, &self.node);
+                                Some(self.network.borrow_mut().add_subscription(data, &self.node))
                             },
-                            _ => { println!("No subscription data") }
+                            _ => { Some(Err(SignalError::MissingField("subscriptionData"))) }
                     }
                 },
             Some("connection-request") => {
-                match json_message["endpoint"].as_str() {
-                    Some(endpoint) => {
-                        let user_sending_request = self.node.borrow().owner.clone().unwrap();
-                        self.network.borrow().send_push(&user_sending_request, &endpoint);
+                // Connection requests are calls, which ring with a push if the endpoint is offline
+                Some(self.start_call(envelope))
+            },
+            _ => { None /* Do nothing if the user is not interested in the push */ }
+        }
+    }
+
+    fn handle_room_request(&self, envelope: &Envelope) -> SignalResult {
+        let room_name = envelope.room.as_ref().ok_or(SignalError::MissingField("room"))?;
+        let mut network = self.network.borrow_mut();
+
+        match envelope.kind {
+            MessageType::JoinRoom => {
+                // The first user to join a room creates it, and decides if it is private
+                let exists = network.rooms.borrow().contains(room_name.as_str());
+                if !exists {
+                    self.within_room_quota(&mut network)?;
+                    let mut room = Room::new(room_name);
+                    room.owner.replace(self.node.borrow().owner.clone());
+                    room.password_hash = envelope.password.as_deref().map(hash_password);
+                    room.invite_only = envelope.private;
+                    room.capacity = envelope.capacity;
+                    room.hidden.set(envelope.hidden.unwrap_or_default());
+                    if envelope.topic.as_ref().is_some_and(|topic| topic.len() > MAX_TOPIC_SIZE) {
+                        return Err(SignalError::TooLarge("topic"));
                     }
-                    _ => { println!("No endpoint for connection request") }
+                    room.topic.replace(envelope.topic.clone());
+                    if let Some(metadata) = envelope.metadata.as_ref() {
+                        if metadata.to_string().len() > MAX_METADATA_SIZE {
+                            return Err(SignalError::TooLarge("metadata"));
+                        }
+                        room.metadata.replace(metadata.clone());
+                    }
+                    network.create_room(room);
+                }
+                network.add_user_to_room(room_name, &self.node,
+                    envelope.password.as_deref(), envelope.invite.as_deref(), envelope.wait)
+            },
+            MessageType::LeaveRoom => network.remove_user_from_room(room_name, &self.node),
+            MessageType::CreateInvite => {
+                let invite = network.create_invite(room_name, &self.node)?;
+                self.node.borrow().sender.send(invite_frame(room_name, &invite, envelope.reference())).ok();
+                Ok(())
+            },
+            MessageType::RevokeInvite => {
+                let invite = envelope.invite.as_ref().ok_or(SignalError::MissingField("invite"))?;
+                network.revoke_invite(room_name, invite, &self.node)
+            },
+            MessageType::UpdateRoom => {
+                network.update_room(room_name, envelope.topic.as_ref(), envelope.metadata.as_ref(), envelope.hidden, &self.node)
+            },
+            MessageType::GetState => {
+                let rooms = network.rooms.borrow();
+                let room = rooms.get(room_name.as_str())
+                    .ok_or_else(|| SignalError::RoomNotFound(room_name.clone()))?;
+                if !room.has_member(&self.node) {
+                    return Err(SignalError::NotInRoom(room_name.clone()));
                 }
+                let frame = room_state_frame(room, envelope.key.as_ref(), envelope.reference());
+                self.node.borrow().sender.send(frame).ok();
+                Ok(())
             },
-            _ => { /* Do nothing if the user is not interested in the push */ }
+            MessageType::SetState => {
+                let key = envelope.key.as_ref().ok_or(SignalError::MissingField("key"))?;
+                let value = envelope.value.as_ref().ok_or(SignalError::MissingField("value"))?;
+                network.change_room_state(room_name, key, Some(value), &self.node)
+            },
+            MessageType::DeleteState => {
+                let key = envelope.key.as_ref().ok_or(SignalError::MissingField("key"))?;
+                network.change_room_state(room_name, key, None, &self.node)
+            },
+            kind => match kind.moderation() {
+                Some(_) if envelope.users.is_empty() => Err(SignalError::MissingField("users")),
+                Some(action) => network.moderate(room_name, action, &envelope.users, &self.node),
+                None => Ok(()),
+            },
+        }
+    }
+
+    fn handle_list_request(&self, envelope: &Envelope) -> SignalResult {
+        let frame = self.network.borrow_mut().list_rooms(
+            envelope.prefix.as_deref(), envelope.after.as_deref(), envelope.limit, envelope.reference()
+        );
+        self.node.borrow().sender.send(frame).ok();
+        Ok(())
+    }
+
+    fn handle_presence_request(&self, envelope: &Envelope) -> SignalResult {
+        let mut network = self.network.borrow_mut();
+
+        match envelope.kind {
+            MessageType::Watch | MessageType::Unwatch if envelope.users.is_empty() => {
+                Err(SignalError::MissingField("users"))
+            },
+            MessageType::Watch => {
+                network.watch(&envelope.users, &self.node);
+                Ok(())
+            },
+            MessageType::Unwatch => {
+                network.unwatch(&envelope.users, &self.node);
+                Ok(())
+            },
+            MessageType::SetStatus => network.set_status(envelope.status.clone(), &self.node),
+            _ => Ok(()),
+        }
+    }
+
+    /// Calls the user in the "to" field, and schedules the ring timeout on this connection.
+    fn start_call(&self, envelope: &Envelope) -> SignalResult {
+        let callee = envelope.to.as_ref().ok_or(SignalError::MissingField("to"))?;
+        let timeout_token = self.network.borrow_mut()
+            .start_call(callee, envelope.payload.clone(), &self.node)?;
+
+        let ring_timeout = self.network.borrow().config.ring_timeout;
+        self.node.borrow().sender.timeout(ring_timeout.as_millis() as u64, Token(timeout_token)).ok();
+        Ok(())
+    }
+
+    fn handle_call_request(&self, envelope: &Envelope) -> SignalResult {
+        let state = match envelope.kind {
+            MessageType::Call => return self.start_call(envelope),
+            MessageType::AcceptCall => CallState::Accepted,
+            MessageType::RejectCall => CallState::Rejected,
+            MessageType::CancelCall => CallState::Cancelled,
+            _ => return Ok(()),
         };
+
+        let call_id = envelope.call.as_ref().ok_or(SignalError::MissingField("call"))?;
+        self.network.borrow_mut().answer_call(call_id, state, &self.node)
+    }
+
+    fn handle_ice_request(&self, envelope: &Envelope) -> SignalResult {
+        let owner = self.node.borrow().owner.clone().ok_or(SignalError::Anonymous)?;
+        let ice_servers = turn::ice_servers(&self.network.borrow().config, &owner);
+        self.node.borrow().sender.send(ice_servers_frame(ice_servers, envelope.reference())).ok();
+        Ok(())
     }
 
-    fn handle_connection_request(&self, json_message: &Value, text_message: &str) -> Result<()> {
-        // !!! WARNING !!!
-        // The word "protocol" match is protcol specific.
-        // Thus a client should make sure to send a viable protocol
-        let protocol = match json_message["protocol"].as_str() {
-            Some(desired_protocol) => { Some(desired_protocol) },
-            _ => { None }
+    /// Answers a plain HTTP request for ICE servers, e.g. `GET /ice-servers?user=alice&resume=<token>`.
+    /// The user is authenticated with a JWT if authentication is enabled, and otherwise
+    /// with the resume token of its session, which requires a resume grace period to be configured.
+    /// Pages on an allowed origin may read the response, since the origin was checked before.
+    fn ice_servers_response(&self, request: &Request, mut query: Query) -> Response {
+        let owner = if self.network.borrow().authenticator.is_some() {
+            self.authenticate(request, &mut query).ok().and(query.user)
+        } else {
+            let network = self.network.borrow();
+            match (query.user, query.resume.as_ref()) {
+                (Some(user), Some(token)) if network.can_resume(&user, token) => Some(user),
+                _ => None,
+            }
         };
 
+        let mut response = match owner {
+            Some(owner) => {
+                let body = turn::ice_servers(&self.network.borrow().config, &owner).to_string();
+                Response::new(200, "OK", body.into_bytes())
+            },
+            None => {
+                let body = SignalError::Unauthorized.to_frame(None);
+                Response::new(403, "Forbidden", body.into_bytes())
+            },
+        };
+        let headers = response.headers_mut();
+        headers.push(("Content-Type".into(), b"application/json".to_vec()));
+        if let Ok(Some(origin)) = request.origin() {
+            headers.push(("Access-Control-Allow-Origin".into(), origin.as_bytes().to_vec()));
+            headers.push(("Vary".into(), b"Origin".to_vec()));
+        }
+        response
+    }
 
-        // The words below are protcol specific.
+    /// Answers a plain HTTP request for the usage of a tenant, e.g. `GET /usage?api_key=<key>`.
+    fn usage_response(&self) -> Response {
+        let mut response = match self.tenants.borrow().usage(&self.tenant) {
+            Some(usage) => Response::new(200, "OK", usage.to_string().into_bytes()),
+            None => rejection(&SignalError::Unauthorized),
+        };
+        response.headers_mut().push(("Content-Type".into(), b"application/json".to_vec()));
+        response
+    }
+
+    /// Picks the tenant of a handshake. A node connecting with an API key belongs to its tenant,
+    /// which is required once tenants are configured. Otherwise the tenant is picked by the origin.
+    fn tenant_of(&self, request: &Request, query: &Query) -> std::result::Result<String, SignalError> {
+        let tenants = self.tenants.borrow();
+        let api_key = query.api_key.as_deref()
+            .or_else(|| request.header("X-Api-Key").and_then(|header| str::from_utf8(header).ok()));
+
+        match api_key {
+            Some(api_key) => tenants.tenant_for_api_key(api_key).ok_or_else(|| {
+                println!("Rejected a handshake with an unknown API key");
+                SignalError::Unauthorized
+            }),
+            None if tenants.has_accounts() => Err(SignalError::Unauthorized),
+            None => {
+                let origin = request.origin().unwrap_or_default();
+                Ok(self.network.borrow().config.tenant_for_origin(origin))
+            },
+        }
+    }
+
+    /// Checks that the tenant's quota of rooms allows another room to be created on its network.
+    fn within_room_quota(&self, network: &mut Network) -> SignalResult {
+        self.tenants.borrow_mut().admit_room(&self.tenant, network.room_count())
+    }
+
+    /// Authenticates the handshake with a JWT if authentication is enabled,
+    /// and sets the username of the query to the one the token was issued for.
+    /// The token is taken from the query, an `Authorization` header or the offered subprotocols.
+    /// Returns the subprotocol to accept if the token was offered as one.
+    fn authenticate(&self, request: &Request, query: &mut Query) -> std::result::Result<Option<String>, SignalError> {
+        let network = self.network.borrow();
+        let authenticator = match network.authenticator.as_ref() {
+            Some(authenticator) => authenticator,
+            None if query.user.is_some() => return Ok(None),
+            None => return Err(SignalError::BadHandshake("A 'user' parameter is required".to_string())),
+        };
+
+        let protocols = request.protocols().unwrap_or_default();
+        let protocol_token = auth::token_from_protocols(&protocols);
+        let token = query.token.as_deref()
+            .or_else(|| request.header("Authorization").and_then(|header| auth::token_from_authorization(header)))
+            .or(protocol_token)
+            .ok_or(SignalError::Unauthorized)?;
+
+        let username = authenticator.authenticate(token).map_err(|reason| {
+            // The resource is not logged, since it may contain the token
+            println!("Rejected an unauthenticated handshake: {}", reason);
+            SignalError::Unauthorized
+        })?;
+        if query.user.as_ref().is_some_and(|user| *user != username) {
+            println!("Rejected an unauthenticated handshake: the token was issued for {:?}", username);
+            return Err(SignalError::Unauthorized);
+        }
+        query.user = Some(username);
+
+        // Browsers fail the connection unless one of the offered subprotocols is accepted
+        Ok(protocol_token.filter(|offered| *offered == token).map(|token| {
+            protocols.iter().find(|protocol| **protocol != token).unwrap_or(&token).to_string()
+        }))
+    }
+
+    /// Validates the session descriptions and candidates of a signaling message, if configured to,
+    /// before relaying it.
+    fn handle_signal(&self, envelope: &mut Envelope) -> DeliveryResult {
+        let verdict = {
+            let network = self.network.borrow();
+            if network.config.validate_sdp {
+                sdp::sanitize(envelope, &network.config).map_err(SignalError::InvalidSdp)?
+            } else {
+                Verdict::Relay
+            }
+        };
+
+        if verdict == Verdict::Drop {
+            return Ok(Delivery::Filtered);
+        }
+
+        if let (Some(Protocol::OneToOne), Some(from), Some(to)) = (envelope.protocol, &envelope.from, &envelope.to) {
+            if let Some(description_type) = negotiation::description_type(envelope) {
+                if !self.network.borrow_mut().negotiate(from, to, description_type) {
+                    return Ok(Delivery::Superseded);
+                }
+            }
+        }
+
+        self.handle_connection_request(envelope)
+    }
+
+    fn handle_connection_request(&self, envelope: &Envelope) -> DeliveryResult {
+        // The sender is stamped by the server, so peers can trust the "from" field
+        let text_message = envelope.to_text();
+
+        // The protocols below are the only ones supported.
         // Thus a client should make sure to use a viable protocol
-        match protocol {
-            Some("one-to-all") => {
-                self.node.borrow().sender.broadcast(text_message)
+        match envelope.protocol {
+            Some(Protocol::OneToAll) => {
+                // The sender of ws would broadcast to every connection on the server, across tenants
+                self.network.borrow().broadcast(&text_message);
+                Ok(Delivery::Delivered)
             },
-            Some("one-to-self") => {
+            Some(Protocol::OneToSelf) => {
                 self.node.borrow().sender.send(text_message)
+                    .map(|()| Delivery::Delivered)
+                    .map_err(|_| SignalError::PeerOffline(envelope.from.clone().unwrap_or_default()))
             },
-            Some("one-to-room") => {
-                match json_message["room"].as_str() {
-                    Some(room_name) => {
-                        let network = self.network.borrow();
-                        network.rooms.borrow().get(room_name).map(|room| { 
-                            // Send the message to everyone in the room
-                            for node in room.nodes.borrow().iter() {
-                                node.upgrade().map(|upgraded_node| {
-                                    if let Some(owner) = upgraded_node.borrow().owner.as_ref() {
-                                        let from = json_message["from"].as_str();
-                                        if from != Some(owner.as_str()) {
-                                            upgraded_node.borrow().sender.send(text_message);
-                                        }
-                                    }
-                                });
+            Some(Protocol::OneToRoom) => {
+                let room_name = envelope.room.as_ref().ok_or(SignalError::MissingField("room"))?;
+                let network = self.network.borrow();
+                let rooms = network.rooms.borrow();
+                let room = rooms.get(room_name.as_str())
+                    .ok_or_else(|| SignalError::RoomNotFound(room_name.clone()))?;
+                network.may_speak(room, &self.node)?;
+
+                // Send the message to everyone in the room
+                for node in room.nodes.borrow().iter() {
+                    if let Some(upgraded_node) = node.upgrade() {
+                        if let Some(owner) = upgraded_node.borrow().owner.as_ref() {
+                            if envelope.from.as_ref() != Some(owner) {
+                                upgraded_node.borrow().sender.send(text_message.as_str()).ok();
                             }
-                        });
-                        Ok(())
-                    }
-                    _ => {
-                        self.node.borrow().sender.send(
-                            "No field 'room' provided"
-                        )
+                        }
                     }
                 }
+                Ok(Delivery::Delivered)
             },
-            Some("one-to-one") => {
-                match json_message["endpoint"].as_str() {
-                    Some(endpoint) => {
-                        let network = self.network.borrow();
-                        let endpoint_node = network.nodemap.borrow().get(endpoint)
-                            .and_then(|node| node.upgrade());
-
-                        match endpoint_node {
-                            Some(node) => { node.borrow().sender.send(text_message) }
-                            _ => {self.node.borrow().sender
-                                .send("Could not find a node with that name")}
-                        }
-                    }
-                    _ => {
-                        self.node.borrow().sender.send(
-                            "No field 'endpoint' provided"
-                        )
-                    }
+            Some(Protocol::OneToOne) => {
+                let endpoint = envelope.to.as_ref().ok_or(SignalError::MissingField("to"))?;
+                let endpoint_node = self.network.borrow().get_node(endpoint);
+
+                match endpoint_node {
+                    Some(node) => {
+                        let delivery = node.borrow().sender.send(text_message)
+                            .map(|()| Delivery::Delivered)
+                            .map_err(|_| SignalError::PeerOffline(endpoint.clone()));
+                        delivery
+                    },
+                    None if envelope.queue => {
+                        let mut network = self.network.borrow_mut();
+                        let queued = QueuedMessage::new(
+                            text_message, envelope.from.clone(), envelope.id.clone(), network.config.queue_ttl
+                        );
+                        network.enqueue(endpoint, queued).map(|()| Delivery::Queued)
+                    },
+                    None => Err(SignalError::UserNotFound(endpoint.clone())),
                 }
-                
             }
-            _ => {
-                self.node.borrow().sender.send(
-                        "Invalid protocol, valid protocols include: 
-                            'one-to-self'
-                            'one-to-one'
-                            'one-to-room'
-                            'one-to-all'"
-                    )
-                }
+            None => Err(SignalError::MissingProtocol),
         }
     }
 }
 
 impl Handler for Server {
-    fn on_open(&mut self, handshake: Handshake) -> Result<()> {
-        // Get the aruments from a URL
-        // i.e localhost:8000/user=testuser
-        let url_arguments : Vec<&str> = handshake.request.resource()[2..]
-                                .split(|c| c == '&' || c == '=').collect();
-        // Beeing greedy by not collecting pairs
-        // Instead every even number (including 0) will be an identifier
-        // and every odd number will be the assigned value
-        
-        if url_arguments[0] == "user" {
-            let username: &str = url_arguments[1]; // This can panic
-            self.network.borrow_mut().add_user(username, &self.node);
+    fn on_request(&mut self, request: &Request) -> Result<Response> {
+        // Keep web pages from other origins out, before anything else is looked at
+        let origin = request.origin().unwrap_or_default();
+        if !self.network.borrow().config.is_origin_allowed(origin) {
+            let origin = origin.unwrap_or_default().to_string();
+            println!("Rejected handshake from the origin {:?}", origin);
+            return Ok(rejection(&SignalError::OriginNotAllowed(origin)));
+        }
+
+        // Reject handshakes we can not make sense of, before any node is registered
+        let mut query = match Query::parse(request.resource()) {
+            Ok(query) => query,
+            Err(reason) => {
+                // Only the path is logged, since the query and the reason may contain tokens and keys
+                println!("Rejected a malformed handshake for {:?}", path_of(request));
+                return Ok(rejection(&SignalError::BadHandshake(reason)));
+            }
+        };
+
+        // Users and rooms are namespaced by the tenant of the API key or the origin
+        self.tenant = match self.tenant_of(request, &query) {
+            Ok(tenant) => tenant,
+            Err(error) => return Ok(rejection(&error)),
+        };
+        self.network = self.tenants.borrow_mut().network(&self.tenant);
+
+        if path_of(request) == "/usage" {
+            return Ok(self.usage_response());
+        }
+
+        if path_of(request) == "/ice-servers" {
+            return Ok(self.ice_servers_response(request, query));
+        }
+
+        let protocol = match self.authenticate(request, &mut query) {
+            Ok(protocol) => protocol,
+            Err(error) => return Ok(rejection(&error)),
+        };
+
+        if let Some(username) = query.user.as_ref() {
+            let network = self.network.borrow();
+            let resumable = query.resume.as_ref()
+                .is_some_and(|token| network.can_resume(username, token));
+            if network.config.duplicate_usernames == DuplicatePolicy::RejectNew
+                && network.is_taken(username) && !resumable {
+                println!("Rejected handshake for {:?}: the username is taken", username);
+                return Ok(rejection(&SignalError::UsernameTaken(username.clone())));
+            }
         }
 
+        let mut response = Response::from_request(request)?;
+        if let Err(error) = self.tenants.borrow_mut().admit_connection(&self.tenant) {
+            println!("Rejected handshake for the tenant {:?}: {}", self.tenant, error);
+            return Ok(rejection(&error));
+        }
+        self.holds_connection = true;
 
-        if url_arguments[2] == "room" {
-            // TODO  ADD ORIGIN
-            //let origin = handshake.request.origin().unwrap().unwrap();
-            let room_name = url_arguments[3]; //format!("{}/{}", origin, url_arguments[3]); // possible panic
-            self.network.borrow_mut().create_room(&room_name);
-            self.network.borrow_mut().add_user_to_room(&room_name, &self.node);
+        self.query = query;
+        if let Some(protocol) = protocol {
+            response.set_protocol(&protocol);
+        }
+        Ok(response)
+    }
+
+    fn on_open(&mut self, _handshake: Handshake) -> Result<()> {
+        // The query was parsed and validated in on_request
+        // i.e localhost:8000/?user=testuser&room=testroom
+        if let Some(username) = self.query.user.as_ref() {
+            let resume_token = self.query.resume.as_deref();
+            let registered = self.network.borrow_mut().add_user(username, &self.node, resume_token);
+            if let Err(error) = registered {
+                // Another node may have taken the username since the handshake was accepted
+                self.send_error(&error, None)?;
+                return self.node.borrow().sender.close_with_reason(
+                    CloseCode::from(CLOSE_USERNAME_TAKEN), &error.to_string()
+                );
+            }
+        }
+
+        for room_name in self.query.rooms.iter() {
+            let created = {
+                let mut network = self.network.borrow_mut();
+                let exists = network.rooms.borrow().contains(room_name.as_str());
+                if exists {
+                    Ok(())
+                } else {
+                    self.within_room_quota(&mut network).map(|()| {
+                        let room = Room::new(room_name);
+                        room.owner.replace(self.node.borrow().owner.clone());
+                        network.create_room(room);
+                    })
+                }
+            };
+            let joined = created
+                .and_then(|()| self.network.borrow_mut().add_user_to_room(room_name, &self.node, None, None, false));
+            if let Err(error) = joined {
+                self.send_error(&error, None)?;
+            }
         }
 
         println!("Network expanded to {:?} connected nodes", self.network.borrow().size());
@@ -181,21 +555,73 @@ impl Handler for Server {
     }
 
     fn on_message(&mut self, msg: Message) -> Result<()> {
+        // Every frame counts against the limits, including frames that turn out to be malformed
+        let max_messages_per_second = self.network.borrow().config.max_messages_per_second;
+        let admitted = if self.node.borrow_mut().within_rate_limit(max_messages_per_second) {
+            self.tenants.borrow_mut().admit_message(&self.tenant)
+        } else {
+            Err(SignalError::RateLimited)
+        };
+
         let text_message: &str = msg.as_text()?;
-        let json_message: Value = 
-            serde_json::from_str(text_message).unwrap_or(Value::default());
+        let mut envelope = match Envelope::parse(text_message) {
+            Ok(envelope) => envelope,
+            Err(error) => {
+                let error = admitted.err().unwrap_or_else(|| SignalError::MalformedMessage(error.to_string()));
+                return self.send_error(&error, None)
+            }
+        };
+
+        // Never trust the sender a client claims to be
+        envelope.from = self.node.borrow().owner.clone();
+
+        if let Err(error) = admitted {
+            return self.send_outcome(&envelope, Err(error));
+        }
      
         // Use chain of responsibility to handle the requests
         #[cfg(feature = "push")]
-        self.handle_push_requests(&json_message);
+        {
+            if let Some(result) = self.handle_push_requests(&envelope) {
+                return self.send_outcome(&envelope, result.map(|()| Delivery::Delivered));
+            }
+        }
 
-        self.handle_connection_request(&json_message, &text_message)
+        let result = match envelope.kind {
+            MessageType::Signal => self.handle_signal(&mut envelope),
+            MessageType::JoinRoom | MessageType::LeaveRoom | MessageType::CreateInvite | MessageType::RevokeInvite
+                | MessageType::UpdateRoom | MessageType::GetState | MessageType::SetState | MessageType::DeleteState
+                | MessageType::Kick | MessageType::Ban | MessageType::Unban | MessageType::Mute | MessageType::Unmute
+                | MessageType::Promote | MessageType::Demote | MessageType::TransferOwnership => {
+                self.handle_room_request(&envelope).map(|()| Delivery::Delivered)
+            },
+            MessageType::Watch | MessageType::Unwatch | MessageType::SetStatus => {
+                self.handle_presence_request(&envelope).map(|()| Delivery::Delivered)
+            },
+            MessageType::Call | MessageType::AcceptCall | MessageType::RejectCall | MessageType::CancelCall => {
+                self.handle_call_request(&envelope).map(|()| Delivery::Delivered)
+            },
+            MessageType::GetIceServers => {
+                self.handle_ice_request(&envelope).map(|()| Delivery::Delivered)
+            },
+            MessageType::ListRooms => {
+                self.handle_list_request(&envelope).map(|()| Delivery::Delivered)
+            },
+        };
+
+        self.send_outcome(&envelope, result)
+    }
 
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        // The only timeouts scheduled are the ring timeouts of calls
+        self.network.borrow_mut().time_out_call(event.0);
+        Ok(())
     }
 
     fn on_close(&mut self, code: CloseCode, reason: &str) {
         // Remove the node from the network
-        if let Some(owner) = &self.node.borrow().owner {
+        let owner = self.node.borrow().owner.clone();
+        if let Some(owner) = owner {
             match code {
                 CloseCode::Normal =>
                     println!("{:?} is done with the connection.", owner),
@@ -207,7 +633,7 @@ impl Handler for Server {
                     println!("{:?} encountered an error: {:?}", owner, reason),
             };
         
-            self.network.borrow_mut().remove(owner)
+            self.network.borrow_mut().remove(&owner, &self.node)
         }
         
         println!("Network shrinked to {:?} connected nodes\n", self.network.borrow().size());
@@ -219,6 +645,16 @@ impl Handler for Server {
 }
 
 
+impl Drop for Server {
+    fn drop(&mut self) {
+        // The connection is released whether it closed, or failed before it was opened
+        if self.holds_connection {
+            self.tenants.borrow_mut().release_connection(&self.tenant);
+        }
+    }
+}
+
+
 #[cfg(feature = "ssl")]
 fn read_file(name: &str) -> std::io::Result<Vec<u8>> {
     let mut file = File::open(name)?;
@@ -227,6 +663,54 @@ fn read_file(name: &str) -> std::io::Result<Vec<u8>> {
     Ok(buf)
 }
 
+/// The path of the request's resource, without its query.
+fn path_of(request: &Request) -> &str {
+    request.resource().split('?').next().unwrap_or_default()
+}
+
+/// Builds the response rejecting a handshake, with the error frame as the body.
+fn rejection(error: &SignalError) -> Response {
+    let (status, reason) = match error {
+        SignalError::Unauthorized => (401, "Unauthorized"),
+        SignalError::OriginNotAllowed(_) => (403, "Forbidden"),
+        SignalError::UsernameTaken(_) => (409, "Conflict"),
+        SignalError::QuotaExceeded(_) => (429, "Too Many Requests"),
+        _ => (400, "Bad Request"),
+    };
+    Response::new(status, reason, error.to_frame(None).into_bytes())
+}
+
+/// Loads the key tokens are verified with, if authentication is enabled.
+fn load_authenticator(config: &Config) -> Option<Authenticator> {
+    match Authenticator::from_config(config) {
+        Ok(authenticator) => authenticator,
+        Err(error) => panic!("{}", error),
+    }
+}
+
+/// Sets up the tenants, starting with the network of the default tenant,
+/// and loads the tenants file if one is configured.
+fn load_tenants(network: Rc<RefCell<Network>>) -> Tenants {
+    let tenants_file = network.borrow().config.tenants_file.clone();
+    let mut tenants = Tenants::new(network);
+    if let Some(path) = tenants_file {
+        if let Err(error) = tenants.load(&path) {
+            panic!("{}", error);
+        }
+    }
+    tenants
+}
+
+/// Starts the embedded STUN server, if it is enabled.
+fn start_stun_server(config: &Config) {
+    if let Some(address) = config.stun_address {
+        match stun::spawn(address) {
+            Ok(_) => println!("STUN server is listening on udp://{}", address),
+            Err(error) => println!("Could not start the STUN server on {}: {}", address, error),
+        }
+    }
+}
+
 #[cfg(not(feature = "ssl"))]
 pub fn run() {
     // Setup logging
@@ -244,6 +728,7 @@ pub fn run() {
                 .required(true)
                 .index(1),
         )
+        .args(&Config::args())
         .get_matches();
 
     #[cfg(feature = "push")]
@@ -263,6 +748,7 @@ pub fn run() {
                 .required(true)
                 .index(2),
         )
+        .args(&Config::args())
         .get_matches();
     
     println!("------------------------------------");
@@ -274,16 +760,26 @@ pub fn run() {
     println!("-------------------------------------");
     
     let network = Rc::new(RefCell::new(Network::default()));
+    network.borrow_mut().config = Config::from_matches(&matches);
+    let authenticator = load_authenticator(&network.borrow().config);
+    network.borrow_mut().authenticator = authenticator;
+    start_stun_server(&network.borrow().config);
     
     #[cfg(feature = "push")]
     network.borrow_mut().set_vapid_path(matches.value_of("VAPIDKEY").unwrap());    
 
+    let tenants = Rc::new(RefCell::new(load_tenants(network.clone())));
+
     listen(matches.value_of("ADDR").unwrap(),
         |sender| {
             let node = Node::new(sender);
             Server { 
                 node: Rc::new(RefCell::new(node)),
-                network: network.clone()
+                network: network.clone(),
+                tenants: tenants.clone(),
+                tenant: DEFAULT_TENANT.to_string(),
+                holds_connection: false,
+                query: Query::default(),
             }
         }
     ).unwrap()
@@ -323,6 +819,7 @@ pub fn run() {
                 .required(true)
                 .index(4),
         )
+        .args(&Config::args())
         .get_matches();
 
     #[cfg(not(feature = "push"))]
@@ -348,6 +845,7 @@ pub fn run() {
                 .required(true)
                 .index(3),
         )
+        .args(&Config::args())
         .get_matches();
     
     let cert = {
@@ -377,10 +875,16 @@ pub fn run() {
     println!("-------------------------------------");
     
     let network = Rc::new(RefCell::new(Network::default()));
+    network.borrow_mut().config = Config::from_matches(&matches);
+    let authenticator = load_authenticator(&network.borrow().config);
+    network.borrow_mut().authenticator = authenticator;
+    start_stun_server(&network.borrow().config);
 
     #[cfg(feature = "push")]
     network.borrow_mut().set_vapid_path(matches.value_of("VAPIDKEY").unwrap());
 
+    let tenants = Rc::new(RefCell::new(load_tenants(network.clone())));
+
     ws::Builder::new()
         .with_settings(ws::Settings {
             encrypt_server: true,
@@ -392,9 +896,166 @@ pub fn run() {
             Server {
                 node: Rc::new(RefCell::new(node)),
                 ssl: acceptor.clone(),
-                network: network.clone()
+                network: network.clone(),
+                tenants: tenants.clone(),
+                tenant: DEFAULT_TENANT.to_string(),
+                holds_connection: false,
+                query: Query::default(),
             }
         })
         .unwrap().listen(matches.value_of("ADDR").unwrap())
     .unwrap();
-}
\ No newline at end of file
+}
+// The ssl server needs an acceptor, so only the plain server is tested
+#[cfg(all(test, not(feature = "ssl")))]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use node::{TestConnection, test_node};
+
+    fn server(network: &Rc<RefCell<Network>>) -> (Server, TestConnection) {
+        let (node, connection) = test_node();
+        let server = Server {
+            node,
+            network: network.clone(),
+            tenants: Rc::new(RefCell::new(Tenants::new(network.clone()))),
+            tenant: DEFAULT_TENANT.to_string(),
+            holds_connection: false,
+            query: Query::default(),
+        };
+        (server, connection)
+    }
+
+    fn handshake(resource: &str) -> Request {
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+            Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n", resource);
+        Request::parse(request.as_bytes()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn handshakes_for_taken_usernames_are_rejected() {
+        let network = Rc::new(RefCell::new(Network::default()));
+        let (mut alice, _) = server(&network);
+        assert_eq!(alice.on_request(&handshake("/?user=alice")).unwrap().status(), 101);
+        network.borrow_mut().add_user("alice", &alice.node, None).unwrap();
+
+        let (mut impostor, _) = server(&network);
+        assert_eq!(impostor.on_request(&handshake("/?user=alice")).unwrap().status(), 409);
+        assert_eq!(impostor.on_request(&handshake("/?user=bob")).unwrap().status(), 101);
+
+        network.borrow_mut().config.duplicate_usernames = DuplicatePolicy::AutoSuffix;
+        let (mut second, _) = server(&network);
+        assert_eq!(second.on_request(&handshake("/?user=alice")).unwrap().status(), 101);
+    }
+
+    fn envelope(id: Option<&str>) -> Envelope {
+        Envelope { id: id.map(String::from), ..Envelope::default() }
+    }
+
+    #[test]
+    fn messages_with_an_id_are_acknowledged() {
+        let network = Rc::new(RefCell::new(Network::default()));
+        let (server, connection) = server(&network);
+
+        server.send_outcome(&envelope(Some("m1")), Ok(Delivery::Queued)).unwrap();
+        assert_eq!(connection.take(), vec![json!({"type": "ack", "id": "m1", "status": "queued"})]);
+        server.send_outcome(&envelope(None), Ok(Delivery::Delivered)).unwrap();
+        assert!(connection.take().is_empty());
+    }
+
+    #[test]
+    fn undelivered_messages_are_negatively_acknowledged() {
+        let network = Rc::new(RefCell::new(Network::default()));
+        let (server, connection) = server(&network);
+
+        server.send_outcome(&envelope(Some("m1")), Err(SignalError::PeerOffline("bob".to_string()))).unwrap();
+        assert_eq!(connection.take(), vec![json!({"type": "nack", "id": "m1", "reason": "peer-offline"})]);
+
+        // Without an id there is nothing to nack, so the error is reported
+        server.send_outcome(&envelope(None), Err(SignalError::PeerOffline("bob".to_string()))).unwrap();
+        let frames = connection.take();
+        assert_eq!(frames[0]["type"], "error");
+        assert_eq!(frames[0]["code"], "peer-offline");
+    }
+
+    #[test]
+    fn other_errors_are_reported_with_the_id() {
+        let network = Rc::new(RefCell::new(Network::default()));
+        let (server, connection) = server(&network);
+
+        server.send_outcome(&envelope(Some("m1")), Err(SignalError::NotInRoom("lobby".to_string()))).unwrap();
+        let frames = connection.take();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0]["type"], "error");
+        assert_eq!(frames[0]["code"], "not-in-room");
+        assert_eq!(frames[0]["ref"], "m1");
+    }
+
+    #[test]
+    fn malformed_messages_are_reported() {
+        let network = Rc::new(RefCell::new(Network::default()));
+        let (mut server, connection) = server(&network);
+
+        server.on_message(Message::text("not json")).unwrap();
+        assert_eq!(connection.take()[0]["code"], "malformed-message");
+        server.on_message(Message::text(r#"{"type": "signal", "id": "m1"}"#)).unwrap();
+        let frames = connection.take();
+        assert_eq!(frames[0]["code"], "missing-protocol");
+        assert_eq!(frames[0]["ref"], "m1");
+    }
+
+    #[test]
+    fn handshakes_are_rejected_with_the_status_of_the_error() {
+        assert_eq!(rejection(&SignalError::Unauthorized).status(), 401);
+        assert_eq!(rejection(&SignalError::OriginNotAllowed("https://example.com".to_string())).status(), 403);
+        assert_eq!(rejection(&SignalError::UsernameTaken("alice".to_string())).status(), 409);
+        assert_eq!(rejection(&SignalError::QuotaExceeded("connections")).status(), 429);
+        assert_eq!(rejection(&SignalError::BadHandshake("reason".to_string())).status(), 400);
+    }
+
+    fn get(resource: &str, origin: &str) -> Request {
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nOrigin: {}\r\n\r\n", resource, origin);
+        Request::parse(request.as_bytes()).unwrap().unwrap()
+    }
+
+    fn header<'a>(response: &'a Response, name: &str) -> Option<&'a [u8]> {
+        response.headers().iter().find(|(header, _)| header == name).map(|(_, value)| value.as_slice())
+    }
+
+    #[test]
+    fn ice_servers_are_handed_to_sessions_over_http() {
+        let network = Rc::new(RefCell::new(Network::default()));
+        network.borrow_mut().config.resume_grace_period = Some(Duration::from_secs(30));
+        network.borrow_mut().config.ice_urls = vec!["stun:stun.example.com:3478".to_string()];
+        let (alice, _) = server(&network);
+        let token = network.borrow_mut().add_user("alice", &alice.node, None).unwrap().resume_token.unwrap();
+
+        let (mut server, _) = server(&network);
+        let response = server.on_request(&get(&format!("/ice-servers?user=alice&resume={}", token), "https://example.com")).unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(str::from_utf8(response.body()).unwrap().contains("stun:stun.example.com:3478"));
+        assert_eq!(header(&response, "Access-Control-Allow-Origin"), Some(&b"https://example.com"[..]));
+
+        let response = server.on_request(&get("/ice-servers?user=alice&resume=wrong", "https://example.com")).unwrap();
+        assert_eq!(response.status(), 403);
+        assert_eq!(header(&response, "Access-Control-Allow-Origin"), Some(&b"https://example.com"[..]));
+    }
+
+    #[test]
+    fn ice_servers_are_only_served_on_their_own_path() {
+        let network = Rc::new(RefCell::new(Network::default()));
+        let (mut server, _) = server(&network);
+        assert_eq!(server.on_request(&get("/ice-servers?user=alice", "https://example.com")).unwrap().status(), 403);
+        assert_ne!(server.on_request(&handshake("/ice-serversX?user=alice")).unwrap().status(), 403);
+    }
+
+    #[test]
+    fn usage_is_only_served_on_its_own_path() {
+        let network = Rc::new(RefCell::new(Network::default()));
+        let (mut server, _) = server(&network);
+        assert_eq!(server.on_request(&get("/usage", "https://example.com")).unwrap().status(), 401);
+        assert_eq!(server.on_request(&handshake("/usageX?user=alice")).unwrap().status(), 101);
+    }
+}
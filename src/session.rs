@@ -0,0 +1,52 @@
+//! Sessions let a node that lost its connection pick up where it left off.
+//! Every node is given a resume token when it connects. When the connection closes,
+//! the username and room memberships are reserved for a grace period,
+//! and a node connecting with the token within that period gets them back.
+
+use std::time::{Duration, Instant};
+
+use openssl::memcmp;
+use openssl::rand::rand_bytes;
+
+/// A disconnected node's session, waiting to be resumed.
+pub struct Reservation {
+    pub token: String,
+    pub rooms: Vec<String>,
+    pub expires_at: Instant,
+}
+
+impl Reservation {
+    pub fn new(token: String, rooms: Vec<String>, grace_period: Duration) -> Reservation {
+        Reservation {
+            token,
+            rooms,
+            expires_at: Instant::now() + grace_period,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.expires_at
+    }
+}
+
+/// The outcome of registering a node on the network.
+pub struct Session {
+    /// The username the node was registered with.
+    pub owner: String,
+    /// The token the node can resume the session with, if sessions can be resumed.
+    pub resume_token: Option<String>,
+    /// True if a previous session was resumed, including its room memberships.
+    pub resumed: bool,
+}
+
+/// Creates a random token that is safe to put in a URL.
+pub fn generate_token() -> String {
+    let mut bytes = [0; 24];
+    rand_bytes(&mut bytes).expect("Could not generate random bytes");
+    base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Compares tokens in constant time, so they can not be guessed by timing the comparison.
+pub fn tokens_match(expected: &str, given: &str) -> bool {
+    expected.len() == given.len() && memcmp::eq(expected.as_bytes(), given.as_bytes())
+}
@@ -0,0 +1,156 @@
+//! A minimal STUN server, so the signaling host can also be the STUN server its clients use.
+//! It only answers Binding requests (RFC 5389), telling each client the address
+//! its request came from in an XOR-MAPPED-ADDRESS attribute.
+//! Anything else it receives is ignored.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::thread;
+
+const HEADER_LENGTH: usize = 20;
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// Binds the address and answers Binding requests on a background thread.
+pub fn spawn(address: SocketAddr) -> io::Result<thread::JoinHandle<()>> {
+    let socket = UdpSocket::bind(address)?;
+    thread::Builder::new()
+        .name("stun".to_string())
+        .spawn(move || serve(&socket))
+}
+
+fn serve(socket: &UdpSocket) {
+    let mut buffer = [0; 1500];
+    loop {
+        let (length, source) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(error) => {
+                println!("The STUN server could not receive: {}", error);
+                continue;
+            }
+        };
+
+        if let Some(response) = respond(&buffer[..length], source) {
+            if let Err(error) = socket.send_to(&response, source) {
+                println!("The STUN server could not answer {}: {}", source, error);
+            }
+        }
+    }
+}
+
+/// Builds the response to a Binding request from the source address,
+/// or None if the packet is not a well-formed Binding request.
+fn respond(request: &[u8], source: SocketAddr) -> Option<Vec<u8>> {
+    if request.len() < HEADER_LENGTH {
+        return None;
+    }
+
+    let message_type = u16::from_be_bytes([request[0], request[1]]);
+    let message_length = u16::from_be_bytes([request[2], request[3]]) as usize;
+    let cookie = u32::from_be_bytes([request[4], request[5], request[6], request[7]]);
+    if message_type != BINDING_REQUEST
+        || cookie != MAGIC_COOKIE
+        || !message_length.is_multiple_of(4)
+        || HEADER_LENGTH + message_length != request.len()
+    {
+        return None;
+    }
+    let transaction_id = &request[8..HEADER_LENGTH];
+
+    let attribute = xor_mapped_address(source, transaction_id);
+    let mut response = Vec::with_capacity(HEADER_LENGTH + 4 + attribute.len());
+    response.extend_from_slice(&BINDING_SUCCESS.to_be_bytes());
+    response.extend_from_slice(&(4 + attribute.len() as u16).to_be_bytes());
+    response.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    response.extend_from_slice(transaction_id);
+    response.extend_from_slice(&XOR_MAPPED_ADDRESS.to_be_bytes());
+    response.extend_from_slice(&(attribute.len() as u16).to_be_bytes());
+    response.extend_from_slice(&attribute);
+    Some(response)
+}
+
+/// Encodes the value of an XOR-MAPPED-ADDRESS attribute.
+/// The port is XOR'ed with the top half of the magic cookie,
+/// an IPv4 address with the magic cookie,
+/// and an IPv6 address with the magic cookie followed by the transaction id.
+fn xor_mapped_address(address: SocketAddr, transaction_id: &[u8]) -> Vec<u8> {
+    let cookie = MAGIC_COOKIE.to_be_bytes();
+    let port = address.port() ^ (MAGIC_COOKIE >> 16) as u16;
+
+    let (family, ip) = match address.ip() {
+        IpAddr::V4(ip) => (0x01, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (0x02, ip.octets().to_vec()),
+    };
+    let mask = cookie.iter().chain(transaction_id);
+
+    let mut value = vec![0, family];
+    value.extend_from_slice(&port.to_be_bytes());
+    value.extend(ip.iter().zip(mask).map(|(byte, mask)| byte ^ mask));
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRANSACTION_ID: [u8; 12] = [0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae];
+
+    fn binding_request() -> Vec<u8> {
+        let mut request = Vec::new();
+        request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+        request.extend_from_slice(&0u16.to_be_bytes());
+        request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        request.extend_from_slice(&TRANSACTION_ID);
+        request
+    }
+
+    #[test]
+    fn answers_a_binding_request_with_the_ipv4_source() {
+        // The sample IPv4 response of RFC 5769
+        let source: SocketAddr = "192.0.2.1:32853".parse().unwrap();
+        let response = respond(&binding_request(), source).unwrap();
+
+        assert_eq!(&response[..2], &BINDING_SUCCESS.to_be_bytes());
+        assert_eq!(&response[2..4], &12u16.to_be_bytes());
+        assert_eq!(&response[4..8], &MAGIC_COOKIE.to_be_bytes());
+        assert_eq!(&response[8..20], &TRANSACTION_ID);
+        assert_eq!(&response[20..], &[0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43]);
+    }
+
+    #[test]
+    fn encodes_an_ipv6_source() {
+        // The sample IPv6 response of RFC 5769
+        let source: SocketAddr = "[2001:db8:1234:5678:11:2233:4455:6677]:32853".parse().unwrap();
+        let value = xor_mapped_address(source, &TRANSACTION_ID);
+        assert_eq!(value, vec![
+            0x00, 0x02, 0xa1, 0x47,
+            0x01, 0x13, 0xa9, 0xfa, 0xa5, 0xd3, 0xf1, 0x79,
+            0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+        ]);
+    }
+
+    #[test]
+    fn ignores_everything_but_binding_requests() {
+        let source: SocketAddr = "192.0.2.1:32853".parse().unwrap();
+        assert!(respond(&binding_request()[..19], source).is_none());
+
+        let mut wrong_type = binding_request();
+        wrong_type[1] = 0x02;
+        assert!(respond(&wrong_type, source).is_none());
+
+        let mut wrong_cookie = binding_request();
+        wrong_cookie[4] = 0;
+        assert!(respond(&wrong_cookie, source).is_none());
+
+        let mut wrong_length = binding_request();
+        wrong_length[3] = 4;
+        assert!(respond(&wrong_length, source).is_none());
+
+        let mut unaligned = binding_request();
+        unaligned[3] = 1;
+        unaligned.push(0);
+        assert!(respond(&unaligned, source).is_none());
+    }
+}
@@ -0,0 +1,325 @@
+//! Tenants are isolated namespaces of users and rooms, so that e.g. two websites
+//! using the same server never see each other's users or rooms.
+//! Every tenant has a network of its own, created when its first node connects.
+//! Nodes are assigned a tenant by the `Origin` of their handshake, see `Config::tenant_for_origin`.
+//!
+//! Tenants can also be configured in a JSON file, e.g.
+//! `[{"name": "chat", "apiKey": "...", "maxConnections": 1000, "maxRooms": 100, "maxMessagesPerSecond": 500}]`.
+//! A node of such a tenant connects with its API key, and the tenant's quotas
+//! are enforced over all of its nodes. Quotas that are left out are unlimited.
+//! Once tenants are configured, every node must connect with an API key.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use error::{SignalError, SignalResult};
+use network::Network;
+use session::tokens_match;
+
+/// The tenant of nodes that are not assigned another one.
+pub const DEFAULT_TENANT: &str = "default";
+
+/// A tenant as configured in the tenants file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TenantConfig {
+    pub name: String,
+    pub api_key: String,
+    pub max_connections: Option<usize>,
+    pub max_rooms: Option<usize>,
+    pub max_messages_per_second: Option<u32>,
+}
+
+/// What a configured tenant has used, and how often it ran into its quotas.
+#[derive(Debug, Default)]
+struct Usage {
+    connections: usize,
+    messages: u64,
+    rejected_connections: u64,
+    rejected_rooms: u64,
+    rejected_messages: u64,
+    window_start: Option<Instant>,
+    messages_in_window: u32,
+}
+
+struct Account {
+    config: TenantConfig,
+    usage: Usage,
+}
+
+pub struct Tenants {
+    networks: HashMap<String, Rc<RefCell<Network>>>,
+    /// The tenants configured with an API key, keyed on their name.
+    accounts: HashMap<String, Account>,
+}
+
+impl Tenants {
+    /// Starts with the network of the default tenant,
+    /// which the networks of other tenants are configured like.
+    pub fn new(default_network: Rc<RefCell<Network>>) -> Tenants {
+        let mut networks = HashMap::new();
+        networks.insert(DEFAULT_TENANT.to_string(), default_network);
+        Tenants { networks, accounts: HashMap::new() }
+    }
+
+    /// Loads the tenants configured in a JSON file.
+    pub fn load(&mut self, path: &str) -> Result<(), String> {
+        let json = fs::read(path)
+            .map_err(|error| format!("Could not read the tenants file {:?}: {}", path, error))?;
+        let tenants: Vec<TenantConfig> = serde_json::from_slice(&json)
+            .map_err(|error| format!("Could not parse the tenants file {:?}: {}", path, error))?;
+
+        for tenant in tenants {
+            if tenant.name.is_empty() || tenant.name == DEFAULT_TENANT {
+                return Err(format!("{:?} is not a valid tenant name", tenant.name));
+            }
+            if tenant.api_key.is_empty() {
+                return Err(format!("The tenant {:?} has no API key", tenant.name));
+            }
+            if self.accounts.contains_key(&tenant.name) {
+                return Err(format!("The tenant {:?} is configured more than once", tenant.name));
+            }
+            self.accounts.insert(tenant.name.clone(), Account { config: tenant, usage: Usage::default() });
+        }
+        Ok(())
+    }
+
+    /// Checks if any tenants are configured, in which case nodes must connect with an API key.
+    pub fn has_accounts(&self) -> bool {
+        !self.accounts.is_empty()
+    }
+
+    /// The name of the tenant the API key belongs to.
+    pub fn tenant_for_api_key(&self, api_key: &str) -> Option<String> {
+        self.accounts.values()
+            .find(|account| tokens_match(&account.config.api_key, api_key))
+            .map(|account| account.config.name.clone())
+    }
+
+    /// The network of the tenant, created if the tenant has none.
+    /// Networks of other tenants that nobody uses any more are removed first,
+    /// so tenants that come and go do not pile up.
+    pub fn network(&mut self, tenant: &str) -> Rc<RefCell<Network>> {
+        let accounts = &self.accounts;
+        self.networks.retain(|name, network| {
+            name == DEFAULT_TENANT || name == tenant || accounts.contains_key(name)
+                || Rc::strong_count(network) > 1 || !network.borrow().is_idle()
+        });
+
+        if let Some(network) = self.networks.get(tenant) {
+            return network.clone();
+        }
+
+        let network = Rc::new(RefCell::new(self.networks[DEFAULT_TENANT].borrow().configured_like(tenant)));
+        self.networks.insert(tenant.to_string(), network.clone());
+        println!("Created a network for the tenant {:?}", tenant);
+        network
+    }
+
+    /// Reserves one of the tenant's connections for a handshake, if its quota allows another one.
+    /// The connection must be released when it closes, or when the handshake fails.
+    pub fn admit_connection(&mut self, tenant: &str) -> SignalResult {
+        let account = match self.accounts.get_mut(tenant) {
+            Some(account) => account,
+            None => return Ok(()),
+        };
+        if account.config.max_connections.is_some_and(|max| account.usage.connections >= max) {
+            account.usage.rejected_connections += 1;
+            return Err(SignalError::QuotaExceeded("connections"));
+        }
+        account.usage.connections += 1;
+        Ok(())
+    }
+
+    /// Releases a connection reserved by `admit_connection`.
+    pub fn release_connection(&mut self, tenant: &str) {
+        if let Some(account) = self.accounts.get_mut(tenant) {
+            account.usage.connections = account.usage.connections.saturating_sub(1);
+        }
+    }
+
+    /// Checks that the tenant, which already has the given number of rooms, may create another one.
+    pub fn admit_room(&mut self, tenant: &str, rooms: usize) -> SignalResult {
+        let account = match self.accounts.get_mut(tenant) {
+            Some(account) => account,
+            None => return Ok(()),
+        };
+        if account.config.max_rooms.is_some_and(|max| rooms >= max) {
+            account.usage.rejected_rooms += 1;
+            return Err(SignalError::QuotaExceeded("rooms"));
+        }
+        Ok(())
+    }
+
+    /// Counts a message against the tenant's quota of messages per second, over all of its nodes.
+    pub fn admit_message(&mut self, tenant: &str) -> SignalResult {
+        let account = match self.accounts.get_mut(tenant) {
+            Some(account) => account,
+            None => return Ok(()),
+        };
+        let usage = &mut account.usage;
+        if usage.window_start.is_none_or(|start| start.elapsed() >= Duration::from_secs(1)) {
+            usage.window_start = Some(Instant::now());
+            usage.messages_in_window = 0;
+        }
+
+        usage.messages_in_window += 1;
+        if account.config.max_messages_per_second.is_some_and(|max| usage.messages_in_window > max) {
+            usage.rejected_messages += 1;
+            return Err(SignalError::QuotaExceeded("messages per second"));
+        }
+        usage.messages += 1;
+        Ok(())
+    }
+
+    /// The usage of a configured tenant, next to its quotas.
+    pub fn usage(&self, tenant: &str) -> Option<Value> {
+        let account = self.accounts.get(tenant)?;
+        let rooms = self.networks.get(tenant)
+            .map(|network| network.borrow_mut().room_count())
+            .unwrap_or_default();
+        let (config, usage) = (&account.config, &account.usage);
+        Some(json!({
+            "tenant": config.name,
+            "connections": usage.connections,
+            "rooms": rooms,
+            "messages": usage.messages,
+            "rejected": {
+                "connections": usage.rejected_connections,
+                "rooms": usage.rejected_rooms,
+                "messages": usage.rejected_messages,
+            },
+            "quotas": {
+                "maxConnections": config.max_connections,
+                "maxRooms": config.max_rooms,
+                "maxMessagesPerSecond": config.max_messages_per_second,
+            },
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    use node::test_node;
+
+    /// Writes the tenants file for a test, and loads it.
+    fn load(name: &str, json: &str) -> Result<Tenants, String> {
+        let path = env::temp_dir().join(format!("rustysignal-{}-{}.json", name, process::id()));
+        fs::write(&path, json).unwrap();
+        let mut tenants = Tenants::new(Rc::new(RefCell::new(Network::default())));
+        let loaded = tenants.load(path.to_str().unwrap());
+        fs::remove_file(&path).ok();
+        loaded.map(|_| tenants)
+    }
+
+    const TENANTS: &str = r#"[
+        {"name": "chat", "apiKey": "k-chat", "maxConnections": 2, "maxRooms": 1, "maxMessagesPerSecond": 3},
+        {"name": "video", "apiKey": "k-video"}
+    ]"#;
+
+    #[test]
+    fn loads_tenants_and_finds_them_by_api_key() {
+        let tenants = load("valid", TENANTS).unwrap();
+        assert!(tenants.has_accounts());
+        assert_eq!(tenants.tenant_for_api_key("k-chat"), Some("chat".to_string()));
+        assert_eq!(tenants.tenant_for_api_key("k-video"), Some("video".to_string()));
+        assert_eq!(tenants.tenant_for_api_key("k-chat2"), None);
+        assert_eq!(tenants.tenant_for_api_key(""), None);
+    }
+
+    #[test]
+    fn rejects_invalid_tenants_files() {
+        assert!(load("syntax", "[{").is_err());
+        assert!(load("default", r#"[{"name": "default", "apiKey": "k"}]"#).is_err());
+        assert!(load("nameless", r#"[{"name": "", "apiKey": "k"}]"#).is_err());
+        assert!(load("keyless", r#"[{"name": "chat", "apiKey": ""}]"#).is_err());
+        assert!(load("twice", r#"[{"name": "chat", "apiKey": "a"}, {"name": "chat", "apiKey": "b"}]"#).is_err());
+        assert!(load("empty", "[]").is_ok_and(|tenants| !tenants.has_accounts()));
+    }
+
+    #[test]
+    fn connections_are_reserved_and_released() {
+        let mut tenants = load("connections", TENANTS).unwrap();
+        assert_eq!(tenants.admit_connection("chat"), Ok(()));
+        assert_eq!(tenants.admit_connection("chat"), Ok(()));
+        assert_eq!(tenants.admit_connection("chat"), Err(SignalError::QuotaExceeded("connections")));
+        tenants.release_connection("chat");
+        assert_eq!(tenants.admit_connection("chat"), Ok(()));
+
+        for _ in 0..10 {
+            assert_eq!(tenants.admit_connection("video"), Ok(()));
+            assert_eq!(tenants.admit_connection("example.com"), Ok(()));
+        }
+        tenants.release_connection("example.com");
+    }
+
+    #[test]
+    fn rooms_and_messages_are_limited() {
+        let mut tenants = load("quotas", TENANTS).unwrap();
+        assert_eq!(tenants.admit_room("chat", 0), Ok(()));
+        assert_eq!(tenants.admit_room("chat", 1), Err(SignalError::QuotaExceeded("rooms")));
+        assert_eq!(tenants.admit_room("video", 100), Ok(()));
+
+        for _ in 0..3 {
+            assert_eq!(tenants.admit_message("chat"), Ok(()));
+        }
+        assert_eq!(tenants.admit_message("chat"), Err(SignalError::QuotaExceeded("messages per second")));
+        assert_eq!(tenants.admit_message("video"), Ok(()));
+    }
+
+    #[test]
+    fn reports_usage_of_configured_tenants() {
+        let mut tenants = load("usage", TENANTS).unwrap();
+        tenants.admit_connection("chat").unwrap();
+        tenants.admit_room("chat", 1).ok();
+        tenants.admit_message("chat").unwrap();
+
+        let usage = tenants.usage("chat").unwrap();
+        assert_eq!(usage["connections"], 1);
+        assert_eq!(usage["messages"], 1);
+        assert_eq!(usage["rejected"]["rooms"], 1);
+        assert_eq!(usage["quotas"]["maxRooms"], 1);
+        assert_eq!(tenants.usage("video").unwrap()["quotas"]["maxRooms"], Value::Null);
+        assert_eq!(tenants.usage("example.com"), None);
+    }
+
+    #[test]
+    fn tenants_have_networks_of_their_own() {
+        let mut tenants = load("networks", TENANTS).unwrap();
+        let chat = tenants.network("chat");
+        let video = tenants.network("video");
+        assert!(Rc::ptr_eq(&chat, &tenants.network("chat")));
+        assert!(!Rc::ptr_eq(&chat, &video));
+
+        let (alice, alice_frames) = test_node();
+        let (bob, bob_frames) = test_node();
+        chat.borrow_mut().add_user("alice", &alice, None).unwrap();
+        video.borrow_mut().add_user("alice", &bob, None).unwrap();
+        alice_frames.take();
+        bob_frames.take();
+
+        chat.borrow().broadcast(r#"{"type":"announcement"}"#);
+        assert_eq!(alice_frames.take().len(), 1);
+        assert!(bob_frames.take().is_empty());
+    }
+
+    #[test]
+    fn unused_networks_of_unconfigured_tenants_are_removed() {
+        let mut tenants = load("pruning", TENANTS).unwrap();
+        drop(tenants.network("example.com"));
+        drop(tenants.network("chat"));
+        tenants.network("example.org");
+        assert!(!tenants.networks.contains_key("example.com"));
+        assert!(tenants.networks.contains_key("chat"));
+    }
+}
@@ -0,0 +1,102 @@
+//! Ephemeral TURN credentials, following the TURN REST API convention
+//! supported by e.g. coturn's `use-auth-secret`.
+//! The username is `<expiry timestamp>:<user>` and the credential is
+//! `base64(hmac-sha1(shared secret, username))`, so the TURN server can verify it
+//! with the shared secret alone, and browsers never see the secret.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde_json::Value;
+
+use config::Config;
+
+/// Builds the ICE servers a user's peer connections should use,
+/// with TURN credentials that expire after the configured time to live.
+pub fn ice_servers(config: &Config, owner: &str) -> Value {
+    let stun_urls: Vec<&String> = config.ice_urls.iter()
+        .filter(|url| url.starts_with("stun:") || url.starts_with("stuns:"))
+        .collect();
+    let turn_urls: Vec<&String> = config.ice_urls.iter()
+        .filter(|url| url.starts_with("turn:") || url.starts_with("turns:"))
+        .collect();
+
+    let mut servers = Vec::new();
+    if !stun_urls.is_empty() {
+        servers.push(json!({"urls": stun_urls}));
+    }
+
+    if let Some(secret) = config.turn_secret.as_ref() {
+        if !turn_urls.is_empty() {
+            let expiry = SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|now| now.as_secs())
+                .unwrap_or_default() + config.turn_ttl.as_secs();
+            let username = format!("{}:{}", expiry, owner);
+            match credential(secret, &username) {
+                Some(credential) => servers.push(json!({
+                    "urls": turn_urls,
+                    "username": username,
+                    "credential": credential,
+                })),
+                None => println!("Could not create TURN credentials for {:?}", owner),
+            }
+        }
+    }
+
+    json!({"iceServers": servers, "ttl": config.turn_ttl.as_secs()})
+}
+
+/// Signs a TURN username with the shared secret.
+fn credential(secret: &str, username: &str) -> Option<String> {
+    let key = PKey::hmac(secret.as_bytes()).ok()?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &key).ok()?;
+    signer.update(username.as_bytes()).ok()?;
+    signer.sign_to_vec().ok().map(|signature| base64::encode(&signature))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(turn_secret: Option<&str>) -> Config {
+        Config {
+            ice_urls: vec![
+                "stun:stun.example.com:3478".to_string(),
+                "turn:turn.example.com:3478?transport=udp".to_string(),
+                "turns:turn.example.com:5349".to_string(),
+            ],
+            turn_secret: turn_secret.map(String::from),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn signs_the_username_with_the_shared_secret() {
+        assert_eq!(credential("north", "1700000000:alice").as_deref(), Some("Cd/49soE35ICqcJF/bCTn8Z4OyE="));
+    }
+
+    #[test]
+    fn hands_out_turn_credentials_for_the_user() {
+        let servers = ice_servers(&config(Some("north")), "alice");
+        assert_eq!(servers["iceServers"][0]["urls"], json!(["stun:stun.example.com:3478"]));
+
+        let turn = &servers["iceServers"][1];
+        assert_eq!(turn["urls"].as_array().map(Vec::len), Some(2));
+        let username = turn["username"].as_str().unwrap();
+        let mut parts = username.splitn(2, ':');
+        let expiry: u64 = parts.next().unwrap().parse().unwrap();
+        assert_eq!(parts.next(), Some("alice"));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert!(expiry > now && expiry <= now + config(None).turn_ttl.as_secs());
+        assert_eq!(turn["credential"].as_str(), credential("north", username).as_deref());
+    }
+
+    #[test]
+    fn hands_out_no_turn_servers_without_a_secret() {
+        let servers = ice_servers(&config(None), "alice");
+        assert_eq!(servers["iceServers"].as_array().map(Vec::len), Some(1));
+        assert!(servers["iceServers"][0].get("credential").is_none());
+    }
+}